@@ -1,15 +1,26 @@
-use crate::data::{self, ObjectType, Oid, RefValue};
+use crate::cache::{FileStat, StatCache};
+use crate::data::{self, Expected, ObjectType, Oid, RefValue};
+use crate::date;
+use crate::diff;
+use crate::error::{Error, Result};
+use crate::parallel;
+use crate::repository::{Repository, GIT_DIR};
+use chrono::{DateTime, FixedOffset, Local};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::{Into, TryFrom};
+use std::env;
 use std::fs::{self, DirEntry};
-use std::io::{Error, ErrorKind, Result};
+use std::io::{ErrorKind, Write};
+use std::iter;
 use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use tempfile::NamedTempFile;
 use walkdir::WalkDir;
 
 fn is_ignored(path: &Path) -> bool {
     for component in path.components() {
         if let Component::Normal(segment) = component {
-            if segment == data::GIT_DIR {
+            if segment == GIT_DIR {
                 return true;
             }
         }
@@ -17,189 +28,504 @@ fn is_ignored(path: &Path) -> bool {
     false
 }
 
-pub fn init() -> Result<()> {
-    data::init()?;
-    data::update_ref(
-        "HEAD",
-        RefValue {
-            symbolic: true,
-            value: Some("refs/heads/master".to_string()),
-        },
-        true,
-    )
+pub struct TreeEntry {
+    pub t: ObjectType,
+    pub oid: Oid,
+    pub name: String,
 }
 
-fn write_tree_entry(dir_entry: DirEntry) -> Result<String> {
-    let path = dir_entry.path();
-    let filename = dir_entry.file_name().into_string().unwrap();
-    return if path.is_dir() {
-        Ok(format!(
-            "{} {} {}",
-            ObjectType::Tree,
-            write_tree(&path)?,
-            filename
-        ))
-    } else {
-        Ok(format!(
-            "{} {} {}",
-            ObjectType::Blob,
-            data::hash_object(&fs::read(&path)?, ObjectType::Blob)?,
-            filename
-        ))
-    };
+fn get_tree_entry(tree_entry: &str) -> Result<TreeEntry> {
+    let bad_entry = || Error::CorruptObject(format!("Bad entry in tree object: {}", tree_entry));
+    let fields: Vec<&str> = tree_entry.splitn(3, ' ').collect();
+    if fields.len() != 3 {
+        return Err(bad_entry());
+    }
+    Ok(TreeEntry {
+        t: ObjectType::try_from(fields[0].as_bytes())?,
+        oid: fields[1].to_string(),
+        name: fields[2].to_string(),
+    })
 }
 
-pub fn write_tree<P: AsRef<Path>>(dir: P) -> Result<String> {
-    let mut tree_contents = Vec::new();
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
+pub type Tree = HashMap<PathBuf, Oid>;
 
-        if is_ignored(&path) {
-            continue;
-        }
+// Name of the file in the git directory holding the tree last read into the
+// working tree by read_tree, which HEAD doesn't point to
+const READ_TREE: &str = "READ_TREE";
 
-        tree_contents.push(write_tree_entry(entry)?)
-    }
-    data::hash_object(tree_contents.join("\n").as_bytes(), ObjectType::Tree)
+/// How checkout and reset treat files with local changes which the target
+/// commit also changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckoutMode {
+    /// Refuse to update the working tree at all
+    Safe,
+    /// Discard the local changes
+    Force,
+    /// Merge the local changes into the target's version, leaving conflict
+    /// markers where they overlap
+    Merge,
 }
 
-struct TreeEntry {
-    t: ObjectType,
-    oid: Oid,
-    name: String,
+// Remove the directories containing a deleted file which it leaves empty, up
+// to the root of the working tree
+fn remove_empty_parents(path: &Path, work_tree: &Path) -> Result<()> {
+    for dir in path.ancestors().skip(1) {
+        if dir == work_tree || !dir.starts_with(work_tree) || fs::read_dir(dir)?.next().is_some() {
+            break;
+        }
+        fs::remove_dir(dir)?;
+    }
+    Ok(())
 }
 
-fn get_tree_entry(tree_entry: &str) -> Option<TreeEntry> {
-    let fields: Vec<&str> = tree_entry.splitn(3, ' ').collect();
-    let t_bytes = fields.get(0)?.as_bytes();
-    Some(TreeEntry {
-        t: ObjectType::try_from(t_bytes).unwrap(),
-        oid: fields.get(1)?.to_string(),
-        name: fields.get(2)?.to_string(),
-    })
+// Files in the working tree which would stop the updates from being written:
+// files (untracked, or tracked but kept) where a written file needs a
+// directory, and anything left in a directory where a file is to be written
+// once the deletions are done
+fn obstructions(work_tree: &Path, updates: &[(PathBuf, Option<Oid>)]) -> Result<Vec<PathBuf>> {
+    let deleted: HashSet<&Path> = updates
+        .iter()
+        .filter(|(_, oid)| oid.is_none())
+        .map(|(path, _)| path.as_path())
+        .collect();
+    let mut found = HashSet::new();
+    for (path, _) in updates.iter().filter(|(_, oid)| oid.is_some()) {
+        for dir in path.ancestors().skip(1) {
+            if dir.as_os_str().is_empty() || deleted.contains(dir) {
+                continue;
+            }
+            match fs::metadata(work_tree.join(dir)) {
+                Ok(metadata) if !metadata.is_dir() => {
+                    found.insert(dir.to_path_buf());
+                }
+                _ => {}
+            }
+        }
+        let full_path = work_tree.join(path);
+        if !full_path.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(&full_path) {
+            let entry = entry?;
+            let inner = entry.path().strip_prefix(work_tree).unwrap_or(entry.path());
+            if !entry.file_type().is_dir() && !deleted.contains(inner) {
+                found.insert(inner.to_path_buf());
+            }
+        }
+    }
+    let mut found: Vec<PathBuf> = found.into_iter().collect();
+    found.sort();
+    Ok(found)
 }
 
-fn get_tree_entries(tree_oid: Option<&Oid>) -> Result<Vec<TreeEntry>> {
-    if tree_oid.is_none() {
-        return Ok(Vec::new());
-    }
-    let tree_oid = tree_oid.unwrap();
-    let tree_contents = data::get_object(&tree_oid.to_string(), Some(ObjectType::Tree))?.contents;
-    let tree_string = String::from_utf8_lossy(&tree_contents);
-    Ok(tree_string
-        .split("\n")
-        .map(|line| get_tree_entry(line).unwrap())
-        .collect())
-}
+impl Repository {
+    // List the files to store from a directory of the working tree,
+    // descending into subdirectories
+    fn list_tree_files(&self, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_ignored(path.strip_prefix(self.work_tree()).unwrap_or(&path)) {
+                continue;
+            }
+            if path.is_dir() {
+                self.list_tree_files(&path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
 
-pub type Tree = HashMap<PathBuf, Oid>;
-pub fn get_tree(tree_oid: Option<&Oid>, base_path: PathBuf) -> Result<Tree> {
-    let mut result = Tree::new();
-    for entry in get_tree_entries(tree_oid)? {
-        if entry.name == "." || entry.name == ".." || entry.name.contains('/') {
-            return Err(Error::new(ErrorKind::Other, "Bad entry in tree object"));
-        }
-        let base_path = Path::new(&base_path);
-        let path = base_path.join(entry.name);
-
-        match entry.t {
-            ObjectType::Blob => {
-                let old_oid = result.insert(path, entry.oid.clone());
-                if let Some(old_oid) = old_oid {
-                    if old_oid != entry.oid {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            "Tree object contains multiple object IDs for the same file",
-                        ));
-                    }
-                }
+    fn write_tree_entry(
+        &self,
+        dir_entry: DirEntry,
+        blobs: &HashMap<PathBuf, Oid>,
+    ) -> Result<String> {
+        let path = dir_entry.path();
+        let filename = dir_entry.file_name().into_string().map_err(|name| {
+            Error::InvalidArgument(format!(
+                "File name is not valid UTF-8: {}",
+                name.to_string_lossy()
+            ))
+        })?;
+        if path.is_dir() {
+            Ok(format!(
+                "{} {} {}",
+                ObjectType::Tree,
+                self.write_subtree(&path, blobs)?,
+                filename
+            ))
+        } else {
+            // A file created since the files were listed is stored now
+            let oid = match blobs.get(&path) {
+                Some(oid) => oid.clone(),
+                None => self.hash_object(&fs::read(&path)?, ObjectType::Blob)?,
+            };
+            Ok(format!("{} {} {}", ObjectType::Blob, oid, filename))
+        }
+    }
+
+    fn write_subtree(&self, dir: &Path, blobs: &HashMap<PathBuf, Oid>) -> Result<Oid> {
+        let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<DirEntry>>>()?;
+        // Entries are sorted so that the same contents always give the same
+        // tree
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut tree_contents = Vec::new();
+        for entry in entries {
+            let path = entry.path();
+
+            if is_ignored(path.strip_prefix(self.work_tree()).unwrap_or(&path)) {
+                continue;
             }
-            ObjectType::Tree => {
-                result.extend(get_tree(Some(&entry.oid), path)?);
+
+            tree_contents.push(self.write_tree_entry(entry, blobs)?)
+        }
+        self.hash_object(tree_contents.join("\n").as_bytes(), ObjectType::Tree)
+    }
+
+    /// Store the contents of a directory within the working tree as a tree
+    /// object. The files are stored on worker threads first, then the trees
+    /// holding them.
+    pub fn write_tree<P: AsRef<Path>>(&self, dir: P) -> Result<String> {
+        let mut files = Vec::new();
+        self.list_tree_files(dir.as_ref(), &mut files)?;
+        let oids = parallel::map(&files, "Writing objects", |path| {
+            self.hash_object(&fs::read(path)?, ObjectType::Blob)
+        })?;
+        let blobs = files.into_iter().zip(oids).collect();
+        self.write_subtree(dir.as_ref(), &blobs)
+    }
+
+    /// List the entries directly within a tree object, without descending
+    /// into subtrees.
+    pub fn get_tree_entries(&self, tree_oid: Option<&Oid>) -> Result<Vec<TreeEntry>> {
+        let tree_oid = match tree_oid {
+            Some(tree_oid) => tree_oid,
+            None => return Ok(Vec::new()),
+        };
+        let tree_contents = self
+            .get_object(&tree_oid.to_string(), Some(ObjectType::Tree))?
+            .contents;
+        let tree_string = String::from_utf8_lossy(&tree_contents);
+        // An empty tree (from an empty directory) has no lines at all
+        tree_string
+            .split('\n')
+            .filter(|line| !line.is_empty())
+            .map(get_tree_entry)
+            .collect()
+    }
+
+    pub fn get_tree(&self, tree_oid: Option<&Oid>, base_path: PathBuf) -> Result<Tree> {
+        let mut result = Tree::new();
+        for entry in self.get_tree_entries(tree_oid)? {
+            if entry.name == "." || entry.name == ".." || entry.name.contains('/') {
+                return Err(Error::CorruptObject(format!(
+                    "Bad entry in tree object: {}",
+                    entry.name
+                )));
             }
-            _ => {
-                // Other object types are not valid to be stored within tree
-                // objects (commit etc)
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Tree object contained object ID for bad type (not blob, tree)",
-                ));
+            let base_path = Path::new(&base_path);
+            let path = base_path.join(&entry.name);
+
+            match entry.t {
+                ObjectType::Blob => {
+                    let old_oid = result.insert(path.clone(), entry.oid.clone());
+                    if let Some(old_oid) = old_oid {
+                        if old_oid != entry.oid {
+                            return Err(Error::CorruptObject(format!(
+                                "Tree object contains multiple object IDs for {}",
+                                path.to_string_lossy()
+                            )));
+                        }
+                    }
+                }
+                ObjectType::Tree => {
+                    result.extend(self.get_tree(Some(&entry.oid), path)?);
+                }
+                _ => {
+                    // Other object types are not valid to be stored within tree
+                    // objects (commit etc)
+                    return Err(Error::CorruptObject(format!(
+                        "Tree object contained {} for {} (not blob, tree)",
+                        entry.t, entry.oid
+                    )));
+                }
             }
         }
+        Ok(result)
     }
-    Ok(result)
-}
 
-pub fn get_working_tree() -> Result<Tree> {
-    let mut result = Tree::new();
-    for entry in WalkDir::new(".") {
-        let entry = entry?;
-        let path = entry.path().strip_prefix(".").unwrap();
-        if is_ignored(&path) || !path.is_file() {
-            continue;
+    /// Hash every file in the working tree on worker threads, without storing
+    /// them as objects. Files whose metadata matches the stat cache aren't
+    /// read again, and the cache is updated with those which were.
+    pub fn get_working_tree(&self) -> Result<Tree> {
+        let cache = self.read_stat_cache()?;
+        let mut files = Vec::new();
+        for entry in WalkDir::new(self.work_tree()) {
+            let entry = entry?;
+            let path = match entry.path().strip_prefix(self.work_tree()) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            if is_ignored(path) {
+                continue;
+            }
+            let stat = match fs::metadata(entry.path()) {
+                Ok(metadata) if metadata.is_file() => FileStat::from(&metadata),
+                _ => continue,
+            };
+            files.push((path.to_path_buf(), stat));
         }
-        result.insert(
-            path.to_path_buf(),
-            data::hash_object(&fs::read(&path)?, ObjectType::Blob)?,
-        );
+
+        // Each file's object ID, and whether it had to be hashed
+        let oids = parallel::map(&files, "Hashing files", |(path, stat)| {
+            Ok(match cache.get(path, stat) {
+                Some(oid) => (oid.clone(), false),
+                None => {
+                    let contents = fs::read(self.work_tree().join(path))?;
+                    (data::compute_oid(&contents, ObjectType::Blob), true)
+                }
+            })
+        })?;
+
+        let mut new_cache = StatCache::default();
+        let mut rehashed = false;
+        let mut result = Tree::new();
+        for ((path, stat), (oid, hashed)) in files.into_iter().zip(oids) {
+            rehashed |= hashed;
+            new_cache.insert(path.clone(), stat, oid.clone());
+            result.insert(path, oid);
+        }
+        // Deleted files also need dropping from the cache
+        if rehashed || new_cache.len() != cache.len() {
+            self.write_stat_cache(&new_cache)?;
+        }
+        Ok(result)
     }
-    Ok(result)
-}
 
-fn clear_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        let path = entry.path();
+    fn get_head_tree(&self) -> Result<Tree> {
+        let tree_oid = match self.get_ref("HEAD", true)?.value {
+            Some(head) => Some(self.get_commit(&head)?.tree),
+            None => None,
+        };
+        self.get_tree(tree_oid.as_ref(), PathBuf::new())
+    }
 
-        if is_ignored(&path) {
-            continue;
+    fn read_tree_path(&self) -> PathBuf {
+        self.git_dir().join(READ_TREE)
+    }
+
+    // The tree last read into the working tree by read_tree, if it hasn't
+    // since been replaced by a commit, checkout or reset
+    fn get_read_tree(&self) -> Result<Option<Tree>> {
+        match fs::read_to_string(self.read_tree_path()) {
+            Ok(oid) => Ok(Some(self.get_tree(Some(&oid), PathBuf::new())?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
         }
+    }
 
-        if path.is_dir() {
-            clear_dir(path)?;
-        } else {
-            fs::remove_file(path)?;
+    fn clear_read_tree(&self) -> Result<()> {
+        match fs::remove_file(self.read_tree_path()) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
         }
     }
-    Ok(())
-}
 
-pub fn read_tree(tree_oid: &Oid) -> Result<()> {
-    let base_path = Path::new(".").to_path_buf();
-    clear_dir(&base_path)?;
-    for (path, oid) in get_tree(Some(tree_oid), base_path)? {
-        if let Some(parent) = path.parent() {
-            if parent.is_dir() {
-                fs::create_dir_all(parent)?;
+    // Write (or, given no object, delete) files in the working tree, touching
+    // nothing else. Nothing may be in the way of the files written (see
+    // obstructions) except empty directories, which are removed.
+    fn update_working_files(&self, mut updates: Vec<(PathBuf, Option<Oid>)>) -> Result<()> {
+        // Files are deleted before any are written, so that a file can be
+        // replaced by a directory of the same name and vice versa
+        updates.sort_by_key(|(_, oid)| oid.is_some());
+        for (path, oid) in updates {
+            let path = self.work_tree().join(path);
+            match oid {
+                Some(oid) => {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    if path.is_dir() {
+                        fs::remove_dir_all(&path)?;
+                    }
+                    fs::write(
+                        path,
+                        self.get_object(&oid, Some(ObjectType::Blob))?.contents,
+                    )?;
+                }
+                None => {
+                    fs::remove_file(&path)?;
+                    remove_empty_parents(&path, self.work_tree())?;
+                }
             }
         }
-        fs::write(
-            path,
-            data::get_object(&oid, Some(ObjectType::Blob))?.contents,
-        )?;
+        Ok(())
+    }
+
+    /// Update the working tree to a tree, as checkout does without moving
+    /// HEAD. Only files which differ from the tree last read (or HEAD's
+    /// tree) are touched, and it is refused if that would lose local
+    /// changes. The tree is remembered, so that its files aren't taken for
+    /// local changes by a later read_tree, checkout or reset.
+    pub fn read_tree(&self, tree_oid: &Oid) -> Result<()> {
+        self.switch_tree(tree_oid, tree_oid, CheckoutMode::Safe)?;
+        let mut file = NamedTempFile::new_in(self.git_dir())?;
+        file.write_all(tree_oid.as_bytes())?;
+        file.persist(self.read_tree_path()).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// Identity and timestamp recorded against a commit, serialized in the same
+/// form as Git: `Name <email> <unix timestamp> <+hhmm offset>`.
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: DateTime<FixedOffset>,
+}
+
+impl Signature {
+    /// Build the signature for a new object from the environment, using the
+    /// `GITOX_<ROLE>_NAME`, `GITOX_<ROLE>_EMAIL` and `GITOX_<ROLE>_DATE`
+    /// variables (e.g. `role` of "AUTHOR") and falling back to the current
+    /// user and local time.
+    pub fn from_env(role: &str) -> Result<Signature> {
+        let var = |field: &str| env::var(format!("GITOX_{}_{}", role, field)).ok();
+        let name = var("NAME")
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_else(|| "unknown".to_string());
+        let email = var("EMAIL").unwrap_or_else(|| format!("{}@localhost", name));
+        let time = match var("DATE") {
+            Some(date) => date::parse_date(&date)?,
+            None => Local::now().into(),
+        };
+        Ok(Signature { name, email, time })
+    }
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} <{}> {}",
+            self.name,
+            self.email,
+            self.time.format("%s %z")
+        )
+    }
+}
+
+impl FromStr for Signature {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let bad_signature = || Error::CorruptObject(format!("Corrupted signature: {}", s));
+        let (name, rest) = s.split_once(" <").ok_or_else(bad_signature)?;
+        let (email, date) = rest.split_once("> ").ok_or_else(bad_signature)?;
+        let time = DateTime::parse_from_str(date, "%s %z").map_err(|_| bad_signature())?;
+        Ok(Signature {
+            name: name.to_string(),
+            email: email.to_string(),
+            time,
+        })
     }
-    Ok(())
 }
 
 pub struct Commit {
     pub tree: Oid,
-    pub parent: Option<Oid>,
+    pub parents: Vec<Oid>,
+    pub author: Option<Signature>,
+    pub committer: Option<Signature>,
+    pub message: String,
+}
+
+impl Commit {
+    /// First line of the commit message.
+    pub fn subject(&self) -> &str {
+        self.message.lines().next().unwrap_or("")
+    }
+
+    /// Remainder of the commit message after the subject line.
+    pub fn body(&self) -> &str {
+        match self.message.split_once('\n') {
+            Some((_, body)) => body.trim_matches('\n'),
+            None => "",
+        }
+    }
+}
+
+/// An annotated tag, which names an object (usually a commit) along with who
+/// tagged it, when, and why.
+pub struct Tag {
+    pub object: Oid,
+    pub t: ObjectType,
+    pub name: String,
+    pub tagger: Option<Signature>,
     pub message: String,
 }
 
-impl Into<String> for Commit {
-    fn into(self) -> String {
+impl From<Tag> for String {
+    fn from(tag_obj: Tag) -> Self {
+        let mut tag = format!(
+            "object {}\ntype {}\ntag {}\n",
+            tag_obj.object, tag_obj.t, tag_obj.name
+        );
+        if let Some(tagger) = tag_obj.tagger {
+            tag.push_str(&format!("tagger {}\n", tagger));
+        }
+        // Message separator is a blank line
+        tag.push('\n');
+        tag.push_str(&tag_obj.message);
+        tag
+    }
+}
+
+impl TryFrom<String> for Tag {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self> {
+        let (header, message) = s.split_once("\n\n").unwrap_or((&s, ""));
+        let mut properties: HashMap<&str, &str> = HashMap::new();
+        for line in header.lines() {
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or_else(|| Error::CorruptObject(format!("Corrupted tag header: {}", line)))?;
+            properties.insert(key, value);
+        }
+        let property = |key: &str| {
+            properties.get(key).ok_or_else(|| {
+                Error::CorruptObject(format!("Tag does not contain '{}' field", key))
+            })
+        };
+
+        Ok(Tag {
+            object: property("object")?.to_string(),
+            t: property("type")?.parse()?,
+            name: property("tag")?.to_string(),
+            tagger: properties.get("tagger").map(|s| s.parse()).transpose()?,
+            message: message.to_string(),
+        })
+    }
+}
+
+impl From<Commit> for String {
+    fn from(commit_obj: Commit) -> Self {
         let mut commit = String::new();
         let mut commit_headers = Vec::new();
-        commit_headers.push(format!("{} {}", ObjectType::Tree, self.tree));
-        if let Some(parent) = self.parent {
+        commit_headers.push(format!("{} {}", ObjectType::Tree, commit_obj.tree));
+        for parent in commit_obj.parents {
             commit_headers.push(format!("parent {}", parent));
         }
+        if let Some(author) = commit_obj.author {
+            commit_headers.push(format!("author {}", author));
+        }
+        if let Some(committer) = commit_obj.committer {
+            commit_headers.push(format!("committer {}", committer));
+        }
         commit.push_str(&commit_headers.join("\n"));
         // Message separator is a blank line
         commit.push_str("\n\n");
-        commit.push_str(&self.message);
+        commit.push_str(&commit_obj.message);
 
         commit
     }
@@ -210,209 +536,558 @@ impl TryFrom<String> for Commit {
     fn try_from(s: String) -> Result<Self> {
         let lines: Vec<&str> = s.split("\n").collect();
         let mut properties: HashMap<&str, &str> = HashMap::new();
+        let mut parents: Vec<Oid> = Vec::new();
         let mut finished_header = false;
         let mut message_lines: Vec<&str> = Vec::new();
 
         for line in lines {
-            if line.is_empty() {
+            if finished_header {
+                message_lines.push(line);
+            } else if line.is_empty() {
                 finished_header = true;
-            } else if !finished_header {
-                let fields: Vec<&str> = line.splitn(2, " ").collect();
-                if fields.len() != 2 {
-                    return Err(Self::Error::new(
-                        ErrorKind::Other,
-                        "Commit file has corrupted property header",
-                    ));
-                }
-                let key = fields.get(0).unwrap();
-                let value = fields.get(1).unwrap();
-                properties.insert(key, value);
             } else {
-                message_lines.push(line);
+                let (key, value) = line.split_once(' ').ok_or_else(|| {
+                    Error::CorruptObject(format!("Corrupted commit header: {}", line))
+                })?;
+                // Merge commits have a parent header for each parent
+                if key == "parent" {
+                    parents.push(value.to_string());
+                } else {
+                    properties.insert(key, value);
+                }
             }
         }
 
         let message = message_lines.join("\n");
 
-        if !properties.contains_key("tree") {
-            return Err(Self::Error::new(
-                ErrorKind::Other,
-                "Commit file does not contain 'tree' field",
-            ));
-        }
+        let tree = properties.get("tree").ok_or_else(|| {
+            Error::CorruptObject("Commit does not contain 'tree' field".to_string())
+        })?;
 
         Ok(Commit {
-            tree: properties.get("tree").unwrap().to_string(),
-            parent: properties.get("parent").map(|s| s.to_string()),
-            message: message,
+            tree: tree.to_string(),
+            parents,
+            author: properties.get("author").map(|s| s.parse()).transpose()?,
+            committer: properties.get("committer").map(|s| s.parse()).transpose()?,
+            message,
         })
     }
 }
 
-pub fn commit(message: &str) -> Result<Oid> {
-    let commit = Commit {
-        tree: write_tree(".")?,
-        parent: data::get_ref("HEAD", true)?.value,
-        message: message.to_string(),
-    };
-    let commit_str: String = commit.into();
-    let oid = data::hash_object(commit_str.as_bytes(), ObjectType::Commit)?;
-    data::update_ref(
-        "HEAD",
-        RefValue {
-            symbolic: false,
-            value: Some(oid.clone()),
-        },
-        true,
-    )?;
-    Ok(oid)
+// The refs a short name given by the user could refer to, in order of
+// preference
+fn ref_paths_to_try(name: &str) -> [String; 4] {
+    let name = if name == "@" { "HEAD" } else { name };
+    [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+    ]
 }
 
-pub fn get_commit(oid: &Oid) -> Result<Commit> {
-    let commit = data::get_object(oid, Some(ObjectType::Commit))?;
-    Ok(Commit::try_from(
-        String::from_utf8_lossy(&commit.contents)
-            .to_owned()
-            .to_string(),
-    )?)
-}
+impl Repository {
+    pub fn commit(&self, message: &str) -> Result<Oid> {
+        self.write_commit(message, Signature::from_env("AUTHOR")?, false)
+    }
 
-pub fn is_branch(name: &str) -> Result<bool> {
-    Ok(data::get_ref(&format!("refs/heads/{}", name), true)?
-        .value
-        .is_some())
-}
+    /// Record the working directory as a new commit on HEAD, signed with the
+    /// user's signing key.
+    pub fn commit_signed(&self, message: &str) -> Result<Oid> {
+        self.write_commit(message, Signature::from_env("AUTHOR")?, true)
+    }
 
-pub fn checkout(name: &str) -> Result<()> {
-    let oid = get_oid(name)?;
-    let commit = get_commit(&oid)?;
-    read_tree(&commit.tree)?;
+    /// Record the working directory as a new commit on HEAD, attributed to the
+    /// given author rather than the current user (who is still the committer).
+    pub fn commit_with_author(&self, message: &str, author: Signature) -> Result<Oid> {
+        self.write_commit(message, author, false)
+    }
 
-    let head = if is_branch(name)? {
-        RefValue {
-            symbolic: true,
-            value: Some(format!("refs/heads/{}", name)),
+    fn write_commit(&self, message: &str, author: Signature, sign: bool) -> Result<Oid> {
+        let head = self.get_ref("HEAD", true)?.value;
+        let commit = Commit {
+            tree: self.write_tree(self.work_tree())?,
+            parents: head.iter().cloned().collect(),
+            author: Some(author),
+            committer: Some(Signature::from_env("COMMITTER")?),
+            message: message.to_string(),
+        };
+        let reason = match commit.parents.is_empty() {
+            true => format!("commit (initial): {}", commit.subject()),
+            false => format!("commit: {}", commit.subject()),
+        };
+        let mut raw = String::from(commit).into_bytes();
+        if sign {
+            raw = self.sign_object(&raw)?;
         }
-    } else {
-        RefValue {
-            symbolic: false,
-            value: Some(oid),
+        let oid = self.hash_object(&raw, ObjectType::Commit)?;
+        // Another commit made while this one was being written would
+        // otherwise be silently dropped from the branch
+        self.compare_and_swap_ref(
+            "HEAD",
+            head.as_deref(),
+            RefValue {
+                symbolic: false,
+                value: Some(oid.clone()),
+            },
+            true,
+            &reason,
+        )?;
+        // The working tree's files are now those of HEAD
+        self.clear_read_tree()?;
+        Ok(oid)
+    }
+
+    pub fn get_commit(&self, oid: &Oid) -> Result<Commit> {
+        let commit = self.get_object(oid, Some(ObjectType::Commit))?;
+        Commit::try_from(String::from_utf8_lossy(&commit.contents).into_owned())
+    }
+
+    pub fn get_tag(&self, oid: &Oid) -> Result<Tag> {
+        let tag = self.get_object(oid, Some(ObjectType::Tag))?;
+        Tag::try_from(String::from_utf8_lossy(&tag.contents).into_owned())
+    }
+
+    /// Follow annotated tags to the object they tag, which for any other
+    /// object is just itself.
+    pub fn peel(&self, oid: &Oid) -> Result<Oid> {
+        let mut oid = oid.clone();
+        loop {
+            match self.get_object(&oid, Some(ObjectType::Tag)) {
+                Ok(tag) => {
+                    oid = Tag::try_from(String::from_utf8_lossy(&tag.contents).into_owned())?.object
+                }
+                Err(Error::WrongObjectType { .. }) => return Ok(oid),
+                Err(e) => return Err(e),
+            }
         }
-    };
+    }
 
-    data::update_ref("HEAD", head, false)
-}
+    /// Resolve a name to a commit, peeling any annotated tags it names.
+    pub fn get_commit_oid(&self, name: &str) -> Result<Oid> {
+        self.peel(&self.get_oid(name)?)
+    }
 
-pub fn reset(oid: Oid) -> Result<()> {
-    let commit = get_commit(&oid)?;
-    read_tree(&commit.tree)?;
-
-    data::update_ref(
-        "HEAD",
-        RefValue {
-            symbolic: false,
-            value: Some(oid),
-        },
-        true,
-    )
-}
+    pub fn is_branch(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .get_ref(&format!("refs/heads/{}", name), true)?
+            .value
+            .is_some())
+    }
 
-pub fn create_tag(name: &str, oid: &Oid) -> Result<()> {
-    let tag_path = format!("refs/tags/{}", name);
-    data::update_ref(
-        &tag_path,
-        RefValue {
-            symbolic: false,
-            value: Some(oid.clone()),
-        },
-        true,
-    )
-}
+    /// Update the working tree from HEAD's tree (or the tree last read into
+    /// it) to another tree. Only files which differ between the two trees
+    /// are touched, so local changes to other files (and untracked files)
+    /// are carried over. Files matching either HEAD's version or the tree
+    /// last read count as unchanged. Returns the files
+    /// left with conflict markers by a merge. Files in the way of the update,
+    /// such as an untracked file where the tree has a directory, are never
+    /// removed, even when forced.
+    fn switch_tree(&self, tree_oid: &Oid, label: &str, mode: CheckoutMode) -> Result<Vec<PathBuf>> {
+        let head_tree = self.get_head_tree()?;
+        let base_tree = match self.get_read_tree()? {
+            Some(tree) => tree,
+            None => head_tree.clone(),
+        };
+        let trees = [
+            base_tree,
+            self.get_tree(Some(tree_oid), PathBuf::new())?,
+            self.get_working_tree()?,
+            head_tree,
+        ];
 
-pub fn create_branch(name: &str, oid: &Oid) -> Result<()> {
-    let branch_path = format!("refs/heads/{}", name);
-    data::update_ref(
-        &branch_path,
-        RefValue {
-            symbolic: false,
-            value: Some(oid.clone()),
-        },
-        true,
-    )
-}
+        let mut updates = Vec::new();
+        let mut merges = Vec::new();
+        let mut blocked = Vec::new();
+        for (path, mut oids) in diff::compare_trees(&trees)? {
+            let head = oids.pop().unwrap();
+            let working = oids.pop().unwrap();
+            let target = oids.pop().unwrap();
+            let base = oids.pop().unwrap();
+            if base == target || working == target {
+                continue;
+            }
+            // The file has local changes if it differs from the version it
+            // was last given
+            let clean = working == base || working == head;
+            match (base, target) {
+                (_, target) if clean || mode == CheckoutMode::Force => updates.push((path, target)),
+                (Some(base), Some(target)) if mode == CheckoutMode::Merge && working.is_some() => {
+                    merges.push((path, base, target))
+                }
+                _ => blocked.push(path),
+            }
+        }
+        blocked.extend(obstructions(self.work_tree(), &updates)?);
+        // Nothing is touched unless the whole checkout can go ahead
+        if !blocked.is_empty() {
+            blocked.sort();
+            blocked.dedup();
+            return Err(Error::DirtyWorktree(blocked));
+        }
+
+        self.update_working_files(updates)?;
+        let mut conflicts = Vec::new();
+        for (path, base, target) in merges {
+            let full_path = self.work_tree().join(&path);
+            let (merged, conflicted) = self.merge_file(&full_path, &base, &target, label)?;
+            fs::write(full_path, merged)?;
+            if conflicted {
+                conflicts.push(path);
+            }
+        }
+        conflicts.sort();
+        self.clear_read_tree()?;
+        Ok(conflicts)
+    }
+
+    /// Switch to a branch or commit, updating the working tree to match it.
+    /// Returns the files left with conflict markers by a merge.
+    pub fn checkout(&self, name: &str, mode: CheckoutMode) -> Result<Vec<PathBuf>> {
+        let from = match self.get_branch_name()? {
+            Some(branch) => branch,
+            None => self.get_oid("HEAD")?,
+        };
+        let oid = self.get_commit_oid(name)?;
+        let commit = self.get_commit(&oid)?;
+        let conflicts = self.switch_tree(&commit.tree, name, mode)?;
+
+        let head = if self.is_branch(name)? {
+            RefValue {
+                symbolic: true,
+                value: Some(format!("refs/heads/{}", name)),
+            }
+        } else {
+            RefValue {
+                symbolic: false,
+                value: Some(oid),
+            }
+        };
 
-pub fn get_branch_name() -> Result<Option<String>> {
-    let head = data::get_ref("HEAD", false)?;
-    if !head.symbolic {
-        return Ok(None);
+        self.update_ref(
+            "HEAD",
+            head,
+            false,
+            &format!("checkout: moving from {} to {}", from, name),
+        )?;
+        Ok(conflicts)
     }
-    let head = head.value;
-    match head {
-        None => Ok(None),
-        Some(refname) => Ok(refname
-            .strip_prefix("refs/heads/")
-            .map(|str_| str_.to_string())),
+
+    /// Move HEAD (or the branch it points to) to a commit, updating the
+    /// working tree to match it. Returns the files left with conflict
+    /// markers by a merge.
+    pub fn reset(&self, oid: Oid, mode: CheckoutMode) -> Result<Vec<PathBuf>> {
+        let commit = self.get_commit(&oid)?;
+        let conflicts = self.switch_tree(&commit.tree, &oid, mode)?;
+
+        self.update_ref(
+            "HEAD",
+            RefValue {
+                symbolic: false,
+                value: Some(oid.clone()),
+            },
+            true,
+            &format!("reset: moving to {}", oid),
+        )?;
+        Ok(conflicts)
     }
-}
 
-pub fn iter_branch_names() -> Result<impl Iterator<Item = String>> {
-    Ok(data::iter_refs(Some("refs/heads/"), true)?
-        .map(|(refname, _)| refname.strip_prefix("refs/heads/").unwrap().to_string()))
-}
+    pub fn create_tag(&self, name: &str, oid: &Oid) -> Result<()> {
+        let tag_path = format!("refs/tags/{}", name);
+        self.update_ref(
+            &tag_path,
+            RefValue {
+                symbolic: false,
+                value: Some(oid.clone()),
+            },
+            true,
+            &format!("tag: tagging {}", oid),
+        )
+    }
 
-/// Attempt to retrieve the OID from a reference, but otherwise return the
-/// reference assuming it is itself an OID.
-pub fn get_oid(ref_: &str) -> Result<Oid> {
-    let ref_translations: HashMap<&str, &str> = [("@", "HEAD")].iter().cloned().collect();
-    let ref_str: &str = ref_translations.get(ref_).unwrap_or(&ref_);
+    /// Create an annotated tag of an object, tagged by the current user and
+    /// optionally signed with their signing key, returning the ID of the new
+    /// tag object.
+    pub fn create_annotated_tag(
+        &self,
+        name: &str,
+        oid: &Oid,
+        message: &str,
+        sign: bool,
+    ) -> Result<Oid> {
+        let tag = Tag {
+            object: oid.clone(),
+            t: self.get_object(oid, None)?.t,
+            name: name.to_string(),
+            tagger: Some(Signature::from_env("COMMITTER")?),
+            message: message.to_string(),
+        };
+        let mut raw = String::from(tag).into_bytes();
+        if sign {
+            raw = self.sign_object(&raw)?;
+        }
+        let tag_oid = self.hash_object(&raw, ObjectType::Tag)?;
+        self.create_tag(name, &tag_oid)?;
+        Ok(tag_oid)
+    }
+
+    /// Delete a tag, returning the object it pointed to.
+    pub fn delete_tag(&self, name: &str) -> Result<Oid> {
+        let tag_path = format!("refs/tags/{}", name);
+        let oid = self
+            .get_ref(&tag_path, false)?
+            .value
+            .ok_or_else(|| Error::UnknownRevision(name.to_string()))?;
+        let mut transaction = self.transaction(&format!("tag: deleted {}", name));
+        transaction.delete(&tag_path, false, Expected::Value(oid.clone()));
+        transaction.commit()?;
+        Ok(oid)
+    }
 
-    let paths_to_try = [
-        format!("{}", ref_str),
-        format!("refs/{}", ref_str),
-        format!("refs/tags/{}", ref_str),
-        format!("refs/heads/{}", ref_str),
-    ];
+    pub fn create_branch(&self, name: &str, oid: &Oid) -> Result<()> {
+        let branch_path = format!("refs/heads/{}", name);
+        self.update_ref(
+            &branch_path,
+            RefValue {
+                symbolic: false,
+                value: Some(oid.clone()),
+            },
+            true,
+            &format!("branch: Created from {}", oid),
+        )
+    }
 
-    for path in &paths_to_try {
-        if let Some(value) = data::get_ref(&path, true)?.value {
-            return Ok(value);
+    /// Delete a branch, returning the commit it pointed to. Unless `force` is
+    /// set, the branch must be merged into HEAD so that no commits are lost.
+    /// The checked out branch can never be deleted.
+    pub fn delete_branch(&self, name: &str, force: bool) -> Result<Oid> {
+        let branch_path = format!("refs/heads/{}", name);
+        let oid = self
+            .get_ref(&branch_path, false)?
+            .value
+            .ok_or_else(|| Error::UnknownRevision(name.to_string()))?;
+        if self.get_branch_name()?.as_deref() == Some(name) {
+            return Err(Error::InvalidArgument(format!(
+                "Cannot delete branch '{}' as it is checked out",
+                name
+            )));
+        }
+        let merged = match self.get_ref("HEAD", true)?.value {
+            Some(head) => self.is_ancestor(&oid, &head)?,
+            None => false,
+        };
+        if !force && !merged {
+            return Err(Error::InvalidArgument(format!(
+                "The branch '{}' is not fully merged (use -D to delete it anyway)",
+                name
+            )));
         }
+
+        let mut transaction = self.transaction(&format!("branch: deleted {}", name));
+        transaction.delete(&branch_path, false, Expected::Value(oid.clone()));
+        transaction.commit()?;
+        Ok(oid)
     }
 
-    if ref_.len() != 40 || ref_.chars().any(|c| !c.is_ascii_hexdigit()) {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("Unknown name given: {}", ref_),
-        ));
+    /// Rename a branch, moving its reflog along with it and keeping HEAD on
+    /// it if it is checked out.
+    pub fn rename_branch(&self, old: &str, new: &str) -> Result<()> {
+        let (old_path, new_path) = (format!("refs/heads/{}", old), format!("refs/heads/{}", new));
+        let oid = self
+            .get_ref(&old_path, false)?
+            .value
+            .ok_or_else(|| Error::UnknownRevision(old.to_string()))?;
+        if self.is_branch(new)? {
+            return Err(Error::InvalidArgument(format!(
+                "A branch named '{}' already exists",
+                new
+            )));
+        }
+
+        let reason = format!("branch: renamed {} to {}", old_path, new_path);
+        let mut transaction = self.transaction(&reason);
+        transaction.delete(&old_path, false, Expected::Value(oid.clone()));
+        transaction.update(
+            &new_path,
+            RefValue {
+                symbolic: false,
+                value: Some(oid),
+            },
+            false,
+            Expected::Missing,
+        );
+        if self.get_branch_name()?.as_deref() == Some(old) {
+            transaction.update(
+                "HEAD",
+                RefValue {
+                    symbolic: true,
+                    value: Some(new_path.clone()),
+                },
+                false,
+                Expected::Value(old_path.clone()),
+            );
+        }
+
+        // The reflog is moved first, so that the rename is recorded at the
+        // end of it, and moved back if the rename fails
+        let (old_log, new_log) = (self.log_path(&old_path), self.log_path(&new_path));
+        let moved_log = old_log.exists();
+        if moved_log {
+            if let Some(parent) = new_log.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&old_log, &new_log)?;
+        }
+        let result = transaction.commit();
+        if result.is_err() && moved_log {
+            fs::rename(&new_log, &old_log)?;
+        }
+        result
     }
 
-    Ok(ref_.to_string())
-}
+    pub fn get_branch_name(&self) -> Result<Option<String>> {
+        let head = self.get_ref("HEAD", false)?;
+        if !head.symbolic {
+            return Ok(None);
+        }
+        let head = head.value;
+        match head {
+            None => Ok(None),
+            Some(refname) => Ok(refname
+                .strip_prefix("refs/heads/")
+                .map(|str_| str_.to_string())),
+        }
+    }
 
-// When using this function, the input Oids should be unique and non-relational
-// (not the parent of another input Oid) to ensure that their order is preserved
-// when they are output from the resulting iterator.
-pub fn iter_commits_and_parents(
-    oids: impl Iterator<Item = Oid>,
-) -> Result<impl Iterator<Item = Oid>> {
-    let mut commits: Vec<Oid> = Vec::new();
-    let mut oidqueue: VecDeque<Oid> = oids.collect();
-    let mut visited: HashSet<Oid> = HashSet::new();
+    pub fn iter_branch_names(&self) -> Result<impl Iterator<Item = String>> {
+        Ok(self
+            .iter_refs(Some("refs/heads/"), true)?
+            .filter_map(|(refname, _)| {
+                refname
+                    .strip_prefix("refs/heads/")
+                    .map(|name| name.to_string())
+            }))
+    }
 
-    while !oidqueue.is_empty() {
-        let oid = oidqueue.pop_front().unwrap();
+    /// Attempt to retrieve the OID from a reference, but otherwise return the
+    /// reference assuming it is itself an OID, which may be abbreviated to
+    /// as few as 4 characters so long as it is unambiguous. Reflog entries
+    /// can be named with `<ref>@{<n>}` or `<ref>@{<date>}`, and annotated
+    /// tags peeled with `<rev>^{}`.
+    pub fn get_oid(&self, ref_: &str) -> Result<Oid> {
+        // "<rev>^{}" peels annotated tags
+        if let Some(name) = ref_.strip_suffix("^{}") {
+            return self.get_commit_oid(name);
+        }
+        // Of the form "<ref>@{<n>}" or "<ref>@{<date>}"
+        if let Some((name, spec)) = ref_.strip_suffix('}').and_then(|r| r.split_once("@{")) {
+            return self.get_reflog_oid(name, spec);
+        }
 
-        if !visited.insert(oid.clone()) {
-            continue;
+        for path in &ref_paths_to_try(ref_) {
+            match self.get_ref(path, true) {
+                Ok(RefValue {
+                    value: Some(value), ..
+                }) => return Ok(value),
+                // Names which can't be refs may still be object IDs
+                Ok(_) | Err(Error::BadRefName(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if data::is_oid(ref_) {
+            return Ok(ref_.to_string());
+        }
+        if ref_.len() < 4 || ref_.len() > 40 || ref_.chars().any(|c| !c.is_ascii_hexdigit()) {
+            return Err(Error::UnknownRevision(ref_.to_string()));
+        }
+        let mut candidates = self.find_objects(ref_)?;
+        match candidates.len() {
+            0 => Err(Error::UnknownRevision(ref_.to_string())),
+            1 => Ok(candidates.remove(0)),
+            _ => Err(Error::AmbiguousRevision {
+                name: ref_.to_string(),
+                candidates,
+            }),
+        }
+    }
+
+    /// The full name of the ref a short name (e.g. "master") refers to, if
+    /// there is one.
+    pub fn full_ref_name(&self, name: &str) -> Result<Option<String>> {
+        for path in &ref_paths_to_try(name) {
+            match self.get_ref(path, false) {
+                Ok(RefValue { value: Some(_), .. }) => return Ok(Some(path.clone())),
+                Ok(_) | Err(Error::BadRefName(_)) => {}
+                Err(e) => return Err(e),
+            }
         }
+        Ok(None)
+    }
 
-        let commit = get_commit(&oid)?;
-        if commit.parent.is_some() {
-            // Return parent next
-            oidqueue.push_front(commit.parent.unwrap());
+    // When using this function, the input Oids should be unique and non-relational
+    // (not the parent of another input Oid) to ensure that their order is preserved
+    // when they are output from the resulting iterator.
+    pub fn iter_commits_and_parents(
+        &self,
+        oids: impl Iterator<Item = Oid>,
+    ) -> Result<impl Iterator<Item = Oid>> {
+        let mut commits: Vec<Oid> = Vec::new();
+        // Annotated tags are followed to the commits they tag
+        let mut oidqueue = oids
+            .map(|oid| self.peel(&oid))
+            .collect::<Result<VecDeque<Oid>>>()?;
+        let mut visited: HashSet<Oid> = HashSet::new();
+
+        while let Some(oid) = oidqueue.pop_front() {
+            if !visited.insert(oid.clone()) {
+                continue;
+            }
+
+            let commit = self.get_commit(&oid)?;
+            let mut parents = commit.parents.into_iter();
+            if let Some(parent) = parents.next() {
+                // Return first parent next
+                oidqueue.push_front(parent);
+            }
+            // Return other parents later
+            oidqueue.extend(parents);
+            commits.push(oid);
         }
-        commits.push(oid);
+
+        Ok(commits.into_iter())
     }
 
-    Ok(commits.into_iter())
+    /// Whether `ancestor` is `commit` itself or in its history.
+    pub fn is_ancestor(&self, ancestor: &Oid, commit: &Oid) -> Result<bool> {
+        Ok(self
+            .iter_commits_and_parents(iter::once(commit.clone()))?
+            .any(|oid| oid == *ancestor))
+    }
+
+    /// Resolve a revision range of the form `<since>..<until>` (where either side
+    /// defaults to HEAD) or a lone `<since>`, which is read as `<since>..HEAD`.
+    pub fn get_range(&self, range: &str) -> Result<(Oid, Oid)> {
+        let (since, until) = range.split_once("..").unwrap_or((range, "HEAD"));
+        let or_head = |name: &str| if name.is_empty() { "HEAD" } else { name }.to_string();
+        Ok((
+            self.get_commit_oid(&or_head(since))?,
+            self.get_commit_oid(&or_head(until))?,
+        ))
+    }
+
+    /// Iterate over the commits reachable from `include` which are not reachable
+    /// from `exclude`, in the same order as `iter_commits_and_parents`.
+    pub fn iter_commits_in_range(
+        &self,
+        exclude: &Oid,
+        include: &Oid,
+    ) -> Result<impl Iterator<Item = Oid>> {
+        let excluded: HashSet<Oid> = self
+            .iter_commits_and_parents(iter::once(exclude.clone()))?
+            .collect();
+        Ok(self
+            .iter_commits_and_parents(iter::once(include.clone()))?
+            .filter(move |oid| !excluded.contains(oid)))
+    }
 }
@@ -0,0 +1,178 @@
+use crate::base::Tree;
+use crate::data::{ObjectType, Oid};
+use crate::diff;
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use std::collections::HashMap;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+/// Attribution of one line of the blamed file.
+pub struct BlameLine {
+    /// Commit which last changed the line
+    pub oid: Oid,
+    /// Path of the file in that commit, which differs if it was renamed since
+    pub path: PathBuf,
+    /// Line number in the file as of that commit
+    pub orig_line: usize,
+    /// Line number in the blamed revision
+    pub final_line: usize,
+    pub content: Vec<u8>,
+}
+
+// Lines yet to be attributed, as pairs of final line number and line number
+// within the version of the file at `path` in a particular commit
+struct Pending {
+    path: PathBuf,
+    lines: Vec<(usize, usize)>,
+}
+
+fn get_commit_tree(repo: &Repository, oid: &Oid) -> Result<Tree> {
+    let commit = repo.get_commit(oid)?;
+    repo.get_tree(Some(&commit.tree), PathBuf::new())
+}
+
+/// Attribute each line of a file in a commit to the commit which last
+/// changed it, by walking back through history and carrying unchanged lines
+/// over to the parent's version of the file (following renames). `range`
+/// optionally restricts the blame to an inclusive range of lines, which may
+/// run past the end of the file.
+pub fn blame(
+    repo: &Repository,
+    oid: &Oid,
+    path: &Path,
+    range: Option<(usize, usize)>,
+) -> Result<Vec<BlameLine>> {
+    let tree = get_commit_tree(repo, oid)?;
+    let blob = tree.get(path).ok_or_else(|| {
+        Error::InvalidArgument(format!(
+            "No such path {} in {}",
+            path.to_string_lossy(),
+            oid
+        ))
+    })?;
+    let contents = repo.get_object(blob, Some(ObjectType::Blob))?.contents;
+    let lines: Vec<&[u8]> = contents.split_inclusive(|c| *c == b'\n').collect();
+
+    if lines.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let (start, end) = range.unwrap_or((1, lines.len()));
+    if start == 0 || start > end {
+        return Err(Error::InvalidArgument(format!(
+            "Invalid line range {},{}",
+            start, end
+        )));
+    }
+    if start > lines.len() {
+        return Err(Error::InvalidArgument(format!(
+            "Line {} is outside the file, which has {} lines",
+            start,
+            lines.len()
+        )));
+    }
+    let end = end.min(lines.len());
+
+    let mut attributed: HashMap<usize, (Oid, PathBuf, usize)> = HashMap::new();
+    let mut pending: HashMap<Oid, Pending> = HashMap::new();
+    let mut trees: HashMap<Oid, Tree> = HashMap::new();
+    pending.insert(
+        oid.clone(),
+        Pending {
+            path: path.to_path_buf(),
+            lines: (start..=end).map(|line| (line, line)).collect(),
+        },
+    );
+    trees.insert(oid.clone(), tree);
+
+    for commit_oid in repo.iter_commits_and_parents(iter::once(oid.clone()))? {
+        let current = match pending.remove(&commit_oid) {
+            Some(current) => current,
+            None => continue,
+        };
+        let tree = match trees.remove(&commit_oid) {
+            Some(tree) => tree,
+            None => get_commit_tree(repo, &commit_oid)?,
+        };
+        let blob = &tree[&current.path];
+
+        // Find the parent's version of the file, which may have had a
+        // different name
+        // Lines are followed through the first parent only
+        let parent = repo.get_commit(&commit_oid)?.parents.into_iter().next();
+        let mut source = None;
+        if let Some(parent) = parent {
+            let parent_tree = get_commit_tree(repo, &parent)?;
+            let parent_path = if parent_tree.contains_key(&current.path) {
+                Some(current.path.clone())
+            } else {
+                repo.find_rename_source(&parent_tree, &tree, &current.path)?
+            };
+            if let Some(parent_path) = parent_path {
+                source = Some((parent, parent_path, parent_tree));
+            }
+        }
+
+        let (parent, parent_path, parent_tree) = match source {
+            Some(source) => source,
+            None => {
+                // The file was created here, so every remaining line is too
+                for (final_line, line) in current.lines {
+                    attributed.insert(final_line, (commit_oid.clone(), current.path.clone(), line));
+                }
+                continue;
+            }
+        };
+
+        let parent_blob = &parent_tree[&parent_path];
+        let last_line = current
+            .lines
+            .iter()
+            .map(|(_, line)| *line)
+            .max()
+            .unwrap_or(0);
+        let mapping = if parent_blob == blob {
+            (1..=last_line).map(Some).collect()
+        } else {
+            let changes = repo.diff_blob_lines(Some(parent_blob), Some(blob))?;
+            diff::map_lines_to_source(&changes, last_line)
+        };
+
+        let mut parent_lines = Vec::new();
+        for (final_line, line) in current.lines {
+            match mapping[line - 1] {
+                Some(parent_line) => parent_lines.push((final_line, parent_line)),
+                None => {
+                    attributed.insert(final_line, (commit_oid.clone(), current.path.clone(), line));
+                }
+            }
+        }
+        if !parent_lines.is_empty() {
+            pending
+                .entry(parent.clone())
+                .or_insert_with(|| Pending {
+                    path: parent_path,
+                    lines: Vec::new(),
+                })
+                .lines
+                .extend(parent_lines);
+            trees.entry(parent).or_insert(parent_tree);
+        }
+    }
+
+    let mut result = Vec::new();
+    for final_line in start..=end {
+        let (oid, path, orig_line) = attributed.remove(&final_line).ok_or_else(|| {
+            Error::CorruptObject("Could not attribute every line of the file".to_string())
+        })?;
+        result.push(BlameLine {
+            oid,
+            path,
+            orig_line,
+            final_line,
+            content: lines[final_line - 1].to_vec(),
+        });
+    }
+    Ok(result)
+}
@@ -0,0 +1,157 @@
+use crate::data::{is_oid, Oid};
+use crate::error::Result;
+use crate::repository::Repository;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+// Name of the file in the git directory caching the object IDs of files in
+// the working tree
+const STAT_CACHE: &str = "stat-cache";
+
+/// The parts of a file's metadata which change whenever it is written, used
+/// to tell whether it may have changed since it was last hashed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileStat {
+    /// Modification time, in seconds and nanoseconds
+    pub mtime: (i64, i64),
+    /// Status change time, in seconds and nanoseconds
+    pub ctime: (i64, i64),
+    pub size: u64,
+    pub ino: u64,
+}
+
+impl From<&Metadata> for FileStat {
+    #[cfg(unix)]
+    fn from(metadata: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        FileStat {
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            size: metadata.size(),
+            ino: metadata.ino(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from(metadata: &Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since| (since.as_secs() as i64, since.subsec_nanos() as i64))
+            .unwrap_or((0, 0));
+        FileStat {
+            mtime,
+            ctime: mtime,
+            size: metadata.len(),
+            ino: 0,
+        }
+    }
+}
+
+// Each entry is stored on one line, in the form
+// `<mtime s> <mtime ns> <ctime s> <ctime ns> <size> <inode> <oid>\t<path>`
+fn parse_entry(line: &str) -> Option<(PathBuf, FileStat, Oid)> {
+    let (fields, path) = line.split_once('\t')?;
+    let fields: Vec<&str> = fields.split(' ').collect();
+    if fields.len() != 7 || !is_oid(fields[6]) {
+        return None;
+    }
+    let stat = FileStat {
+        mtime: (fields[0].parse().ok()?, fields[1].parse().ok()?),
+        ctime: (fields[2].parse().ok()?, fields[3].parse().ok()?),
+        size: fields[4].parse().ok()?,
+        ino: fields[5].parse().ok()?,
+    };
+    Some((PathBuf::from(path), stat, fields[6].to_string()))
+}
+
+/// The object IDs of files in the working tree as of when they were last
+/// hashed, along with their metadata at the time.
+#[derive(Default)]
+pub struct StatCache {
+    entries: HashMap<PathBuf, (FileStat, Oid)>,
+    // Modification time of the cache file when it was read
+    written: Option<(i64, i64)>,
+}
+
+impl StatCache {
+    /// The cached object ID of a file, if its metadata is unchanged since it
+    /// was hashed. A file modified no earlier than the cache was written
+    /// could have changed again within the resolution of its timestamps
+    /// without its metadata changing, so such "racy" entries aren't trusted.
+    pub fn get(&self, path: &Path, stat: &FileStat) -> Option<&Oid> {
+        let (cached, oid) = self.entries.get(path)?;
+        match self.written {
+            Some(written) if cached == stat && stat.mtime < written => Some(oid),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, path: PathBuf, stat: FileStat, oid: Oid) {
+        self.entries.insert(path, (stat, oid));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Repository {
+    fn stat_cache_path(&self) -> PathBuf {
+        self.git_dir().join(STAT_CACHE)
+    }
+
+    /// Load the stat cache, which is empty if it hasn't been written yet.
+    /// Malformed entries are ignored, as they can always be hashed again.
+    pub fn read_stat_cache(&self) -> Result<StatCache> {
+        let path = self.stat_cache_path();
+        let (contents, metadata) = match fs::read_to_string(&path) {
+            Ok(contents) => (contents, fs::metadata(&path)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(StatCache::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let entries = contents
+            .lines()
+            .filter_map(parse_entry)
+            .map(|(path, stat, oid)| (path, (stat, oid)))
+            .collect();
+        Ok(StatCache {
+            entries,
+            written: Some(FileStat::from(&metadata).mtime),
+        })
+    }
+
+    /// Replace the stat cache. Paths which can't be stored on a single line
+    /// are left out.
+    pub fn write_stat_cache(&self, cache: &StatCache) -> Result<()> {
+        let mut file = NamedTempFile::new_in(self.git_dir())?;
+        for (path, (stat, oid)) in &cache.entries {
+            let path = match path.to_str() {
+                Some(path) if !path.contains('\n') => path,
+                _ => continue,
+            };
+            writeln!(
+                file,
+                "{} {} {} {} {} {} {}\t{}",
+                stat.mtime.0,
+                stat.mtime.1,
+                stat.ctime.0,
+                stat.ctime.1,
+                stat.size,
+                stat.ino,
+                oid,
+                path
+            )?;
+        }
+        file.persist(self.stat_cache_path()).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
@@ -1,30 +1,62 @@
-use crate::base;
-use crate::data::{self, ObjectType, Oid};
-use crate::diff;
-use std::collections::HashSet;
+use gitox::base;
+use gitox::blame;
+use gitox::data::{self, Expected, ObjectType, Oid, RefValue, Transaction, NULL_OID};
+use gitox::date;
+use gitox::diff;
+use gitox::graph;
+use gitox::history;
+use gitox::html;
+use gitox::patch;
+use gitox::pretty;
+use gitox::serve;
+use gitox::sign;
+use gitox::tui;
+use gitox::visualize;
+use gitox::{Error, Repository, Result};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs;
-use std::io::{self, Result, Write};
-use std::path::Path;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::process::{Command, Stdio};
 
 pub fn handle(matches: clap::ArgMatches) -> Result<()> {
+    // These commands don't need a repository
     match matches.subcommand() {
-        ("init", Some(submatches)) => init(submatches),
-        ("status", Some(submatches)) => status(submatches),
-        ("k", Some(submatches)) => gitk(submatches),
-        ("hash-file", Some(submatches)) => hash_file(submatches),
-        ("cat-file", Some(submatches)) => cat_file(submatches),
-        ("write-tree", Some(submatches)) => write_tree(submatches),
-        ("read-tree", Some(submatches)) => read_tree(submatches),
-        ("commit", Some(submatches)) => commit(submatches),
-        ("log", Some(submatches)) => log(submatches),
-        ("show", Some(submatches)) => show(submatches),
-        ("diff", Some(submatches)) => diff(submatches),
-        ("checkout", Some(submatches)) => checkout(submatches),
-        ("reset", Some(submatches)) => reset(submatches),
-        ("tag", Some(submatches)) => tag(submatches),
-        ("branch", Some(submatches)) => branch(submatches),
+        ("init", Some(submatches)) => return init(submatches),
+        ("check-ref-format", Some(submatches)) => return check_ref_format(submatches),
+        _ => {}
+    }
+    let repo = open_repository(&matches)?;
+    match matches.subcommand() {
+        ("status", Some(submatches)) => status(&repo, submatches),
+        ("k", Some(submatches)) => gitk(&repo, submatches),
+        ("serve", Some(submatches)) => serve(&repo, submatches),
+        ("tui", Some(submatches)) => tui(&repo, submatches),
+        ("hash-file", Some(submatches)) => hash_file(&repo, submatches),
+        ("cat-file", Some(submatches)) => cat_file(&repo, submatches),
+        ("write-tree", Some(submatches)) => write_tree(&repo, submatches),
+        ("read-tree", Some(submatches)) => read_tree(&repo, submatches),
+        ("update-ref", Some(submatches)) => update_ref(&repo, submatches),
+        ("pack-refs", Some(submatches)) => pack_refs(&repo, submatches),
+        ("gen-key", Some(submatches)) => gen_key(&repo, submatches),
+        ("verify-commit", Some(submatches)) => verify_commit(&repo, submatches),
+        ("verify-tag", Some(submatches)) => verify_tag(&repo, submatches),
+        ("reflog", Some(submatches)) => reflog(&repo, submatches),
+        ("gc", Some(submatches)) => gc(&repo, submatches),
+        ("commit", Some(submatches)) => commit(&repo, submatches),
+        ("log", Some(submatches)) => log(&repo, submatches),
+        ("show", Some(submatches)) => show(&repo, submatches),
+        ("diff", Some(submatches)) => diff(&repo, submatches),
+        ("checkout", Some(submatches)) => checkout(&repo, submatches),
+        ("reset", Some(submatches)) => reset(&repo, submatches),
+        ("tag", Some(submatches)) => tag(&repo, submatches),
+        ("branch", Some(submatches)) => branch(&repo, submatches),
+        ("blame", Some(submatches)) => blame(&repo, submatches),
+        ("format-patch", Some(submatches)) => format_patch(&repo, submatches),
+        ("am", Some(submatches)) => am(&repo, submatches),
+        ("export-html", Some(submatches)) => export_html(&repo, submatches),
         _ => {
             eprintln!("{}", matches.usage());
             exit(1);
@@ -32,13 +64,61 @@ pub fn handle(matches: clap::ArgMatches) -> Result<()> {
     }
 }
 
+// Find the repository to operate on, which contains the current directory
+// unless given by --git-dir and/or --work-tree
+fn open_repository(matches: &clap::ArgMatches<'_>) -> Result<Repository> {
+    match (matches.value_of("git_dir"), matches.value_of("work_tree")) {
+        (Some(git_dir), work_tree) => {
+            // Without a working tree given, the git directory is assumed to
+            // be at the top of its working tree as usual
+            let work_tree = match work_tree {
+                Some(work_tree) => PathBuf::from(work_tree),
+                None => fs::canonicalize(git_dir)?
+                    .parent()
+                    .map(|parent| parent.to_path_buf())
+                    .ok_or_else(|| {
+                        Error::InvalidArgument("--git-dir has no parent directory".to_string())
+                    })?,
+            };
+            Repository::open_with(git_dir, work_tree)
+        }
+        (None, Some(work_tree)) => {
+            let repo = Repository::discover(".")?;
+            Repository::open_with(repo.git_dir(), work_tree)
+        }
+        (None, None) => Repository::discover("."),
+    }
+}
+
+// Convert a path given by the user, relative to the current directory, into
+// a path within the repository's trees
+fn tree_path(repo: &Repository, path: &str) -> Result<PathBuf> {
+    repo.tree_path(&env::current_dir()?, path)
+}
+
 fn init(_submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    base::init()
+    Repository::init(".")?;
+    Ok(())
+}
+
+fn check_ref_format(submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let mut name = submatches.value_of("REFNAME").unwrap().to_string();
+    let normalize = submatches.is_present("normalize");
+    if normalize {
+        // Leading slashes are removed and runs of slashes collapsed
+        let parts: Vec<&str> = name.split('/').filter(|part| !part.is_empty()).collect();
+        name = parts.join("/");
+    }
+    data::check_ref_format(&name, submatches.is_present("allow-onelevel"))?;
+    if normalize {
+        println!("{}", name);
+    }
+    Ok(())
 }
 
-fn status(_submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let head = base::get_oid("HEAD")?;
-    let branch = base::get_branch_name()?;
+fn status(repo: &Repository, _submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let head = repo.get_oid("HEAD")?;
+    let branch = repo.get_branch_name()?;
     if let Some(branch) = branch {
         println!("On branch {}", branch);
     } else {
@@ -46,121 +126,427 @@ fn status(_submatches: &clap::ArgMatches<'_>) -> Result<()> {
     }
 
     println!("\nChanges to be committed:");
-    let head_tree = base::get_commit(&head)?.tree;
+    let head_tree = repo.get_commit(&head)?.tree;
     for (path, action) in diff::iter_changed_files(
-        base::get_tree(Some(&head_tree), Path::new("").to_path_buf())?,
-        base::get_working_tree()?,
+        repo.get_tree(Some(&head_tree), Path::new("").to_path_buf())?,
+        repo.get_working_tree()?,
     )? {
+        let path = repo.display_path(&env::current_dir()?, &path);
         println!("{:>12}: {}", action, path.to_string_lossy());
     }
 
     Ok(())
 }
 
-fn gitk(_submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let mut dot_input: Vec<String> = Vec::new();
-    let mut oids: HashSet<Oid> = HashSet::new();
-
-    dot_input.push("digraph commits {".to_string());
+fn gitk(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let labels: Vec<&str> = submatches
+        .values_of("label")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let options = visualize::Options {
+        depth: parse_count(submatches, "depth")?,
+        refs: submatches
+            .values_of("refs")
+            .map(|refs| refs.map(|r| r.to_string()).collect()),
+        labels: visualize::Labels {
+            subject: labels.contains(&"subject"),
+            author: labels.contains(&"author"),
+        },
+    };
+    let graph = visualize::build(repo, &options)?;
 
-    for (refname, refvalue) in data::iter_refs(None, false)? {
-        if let Some(value) = refvalue.value {
-            dot_input.push(format!("\"{}\" [shape=note]", refname));
-            dot_input.push(format!("\"{}\" -> \"{}\"", refname, value));
-            if !refvalue.symbolic {
-                oids.insert(value);
-            }
+    // The format defaults to that named by the output file's extension
+    let output = submatches.value_of("output");
+    let format = match (submatches.value_of("format"), output) {
+        (Some(format), _) => Some(format),
+        (None, Some(output)) => Path::new(output).extension().and_then(|e| e.to_str()),
+        (None, None) => None,
+    };
+    let format = match format {
+        Some(format) => visualize::Format::parse(format)?,
+        None if output.is_some() => visualize::Format::Dot,
+        None => {
+            // Without an export requested, display the graph with Graphviz
+            let proc = Command::new("dot")
+                .arg("-Tgtk")
+                .arg("/dev/stdin")
+                .stdin(Stdio::piped())
+                .spawn()?;
+            proc.stdin
+                .expect("'dot' did not wait to read stdin")
+                .write_all(graph.to_dot().as_bytes())?;
+            return Ok(());
         }
-    }
+    };
 
-    for oid in base::iter_commits_and_parents(oids.into_iter())? {
-        let commit = base::get_commit(&oid)?;
-        dot_input.push(format!(
-            "\"{}\" [shape=box style=filled label=\"{}\"]",
-            oid,
-            &oid[..10]
-        ));
-        if let Some(parent) = commit.parent {
-            dot_input.push(format!("\"{}\" -> \"{}\"", oid, parent));
-        }
+    let rendered = graph.render(&format) + "\n";
+    match output {
+        Some(path) if path != "-" => fs::write(path, rendered)?,
+        _ => io::stdout().write_all(rendered.as_bytes())?,
     }
+    Ok(())
+}
 
-    dot_input.push("}".to_string());
+fn serve(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let port = match submatches.value_of("port") {
+        Some(port) => port
+            .parse()
+            .map_err(|_| Error::InvalidArgument(format!("Invalid port: {}", port)))?,
+        None => 8000,
+    };
+    serve::serve(repo, port)
+}
 
-    let proc = Command::new("dot")
-        .arg("-Tgtk")
-        .arg("/dev/stdin")
-        .stdin(Stdio::piped())
-        .spawn()?;
-    proc.stdin
-        .expect("'dot' did not wait to read stdin")
-        .write(dot_input.join("\n").as_bytes())?;
-    Ok(())
+fn tui(repo: &Repository, _submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    tui::tui(repo)
 }
 
-fn hash_file(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let oid = data::hash_object(
-        &fs::read(submatches.value_of("FILE").unwrap())?,
-        ObjectType::Blob,
-    )?;
+fn hash_file(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let contents = fs::read(submatches.value_of("FILE").unwrap())?;
+    let oid = match submatches.is_present("write") {
+        true => repo.hash_object(&contents, ObjectType::Blob)?,
+        false => data::compute_oid(&contents, ObjectType::Blob),
+    };
     println!("{}", oid);
     Ok(())
 }
 
-fn cat_file(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let oid = &base::get_oid(submatches.value_of("OID").unwrap())?;
-    let object = data::get_object(&oid, None)?;
+fn cat_file(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let oid = &repo.get_oid(submatches.value_of("OID").unwrap())?;
+    let object = repo.get_object(oid, None)?;
     print!("{}", String::from_utf8_lossy(&object.contents));
     Ok(())
 }
 
-fn write_tree(_submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    println!("{}", base::write_tree(".")?);
+fn write_tree(repo: &Repository, _submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    println!("{}", repo.write_tree(repo.work_tree())?);
     Ok(())
 }
 
-fn read_tree(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    base::read_tree(&base::get_oid(submatches.value_of("OID").unwrap())?)
+fn read_tree(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    repo.read_tree(&repo.get_oid(submatches.value_of("OID").unwrap())?)
 }
 
-fn commit(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let message = submatches.value_of("message").unwrap();
-    println!("{}", base::commit(message)?);
+// Old values are given as object IDs, where the null ID of all zeroes means
+// that the ref must not exist
+fn parse_expected(repo: &Repository, old: Option<&str>) -> Result<Expected> {
+    match old {
+        None => Ok(Expected::Any),
+        Some(NULL_OID) => Ok(Expected::Missing),
+        Some(old) => Ok(Expected::Value(repo.get_oid(old)?)),
+    }
+}
+
+// Queue setting a ref to a new value, where the null object ID deletes it
+// instead, as in git
+fn queue_update<'t, 'r>(
+    repo: &Repository,
+    transaction: &'t mut Transaction<'r>,
+    ref_: &str,
+    new: &str,
+    deref: bool,
+    old: Expected,
+) -> Result<&'t mut Transaction<'r>> {
+    if new == NULL_OID {
+        return Ok(transaction.delete(ref_, deref, old));
+    }
+    let new = RefValue {
+        symbolic: false,
+        value: Some(repo.get_oid(new)?),
+    };
+    Ok(transaction.update(ref_, new, deref, old))
+}
+
+// Add one command read from standard input to a transaction:
+//   update <ref> <new> [<old>]
+//   create <ref> <new>
+//   delete <ref> [<old>]
+//   verify <ref> [<old>]
+fn queue_ref_command(
+    repo: &Repository,
+    transaction: &mut Transaction,
+    line: &str,
+    deref: bool,
+) -> Result<()> {
+    let bad_command = || Error::InvalidArgument(format!("Invalid update-ref command: {}", line));
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let value = |oid: &str| -> Result<RefValue> {
+        Ok(RefValue {
+            symbolic: false,
+            value: Some(repo.get_oid(oid)?),
+        })
+    };
+    match fields.as_slice() {
+        ["update", ref_, new] => queue_update(repo, transaction, ref_, new, deref, Expected::Any)?,
+        ["update", ref_, new, old] => {
+            let old = parse_expected(repo, Some(old))?;
+            queue_update(repo, transaction, ref_, new, deref, old)?
+        }
+        ["create", ref_, new] => transaction.update(ref_, value(new)?, deref, Expected::Missing),
+        ["delete", ref_] => transaction.delete(ref_, deref, Expected::Any),
+        ["delete", ref_, old] => transaction.delete(ref_, deref, parse_expected(repo, Some(old))?),
+        // Unlike the other commands, verifying without an old value checks
+        // that the ref doesn't exist
+        ["verify", ref_] => transaction.verify(ref_, Expected::Missing),
+        ["verify", ref_, old] => transaction.verify(ref_, parse_expected(repo, Some(old))?),
+        _ => return Err(bad_command()),
+    };
     Ok(())
 }
 
-fn print_commit(oid: &Oid, commit: base::Commit, refs: Option<&Vec<String>>) -> Result<()> {
-    let ref_str = if let Some(refs) = refs {
-        format!(" ({})", refs.join(", "))
+fn update_ref(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let deref = !submatches.is_present("no-deref");
+    let mut transaction = repo.transaction(submatches.value_of("message").unwrap_or("update-ref"));
+    if submatches.is_present("stdin") {
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                queue_ref_command(repo, &mut transaction, &line, deref)?;
+            }
+        }
     } else {
-        "".to_string()
+        let ref_ = submatches
+            .value_of("REF")
+            .ok_or_else(|| Error::InvalidArgument("No ref given to update".to_string()))?;
+        if submatches.is_present("delete") {
+            let old = parse_expected(repo, submatches.value_of("NEWVALUE"))?;
+            transaction.delete(ref_, deref, old);
+        } else {
+            let new = submatches.value_of("NEWVALUE").ok_or_else(|| {
+                Error::InvalidArgument(format!("No new value given for {}", ref_))
+            })?;
+            let old = parse_expected(repo, submatches.value_of("OLDVALUE"))?;
+            queue_update(repo, &mut transaction, ref_, new, deref, old)?;
+        }
+    }
+    transaction.commit()
+}
+
+fn pack_refs(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let count = repo.pack_refs(submatches.is_present("all"))?;
+    println!("Packed {} refs", count);
+    Ok(())
+}
+
+fn reflog(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let name = submatches.value_of("REF").unwrap();
+    let ref_ = repo
+        .full_ref_name(name)?
+        .ok_or_else(|| Error::UnknownRevision(name.to_string()))?;
+    for (i, entry) in repo.reflog(&ref_)?.iter().enumerate() {
+        let new = entry.new.as_deref().unwrap_or(NULL_OID);
+        println!("{} {}@{{{}}}: {}", &new[..10], name, i, entry.reason);
+    }
+    Ok(())
+}
+
+fn gc(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let expire = date::parse_date(submatches.value_of("expire").unwrap_or("90 days ago"))?;
+    let expired = repo.expire_reflogs(expire)?;
+    println!("Expired {} reflog entries", expired);
+    Ok(())
+}
+
+fn commit(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let message = submatches.value_of("message").unwrap();
+    let oid = match submatches.is_present("sign") {
+        true => repo.commit_signed(message)?,
+        false => repo.commit(message)?,
     };
-    println!("commit {}{}", oid, ref_str);
-    println!("    {}", commit.message);
-    println!("");
+    println!("{}", oid);
+    Ok(())
+}
+
+fn gen_key(repo: &Repository, _submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    println!("Public key: {}", repo.generate_signing_key()?);
+    Ok(())
+}
+
+// Print the result of checking an object's signature, failing unless it was
+// signed by a trusted key
+fn print_verification(repo: &Repository, oid: &Oid) -> Result<()> {
+    let verification = repo.verify_object(oid)?;
+    match verification {
+        sign::Verification::Good { .. } => {
+            println!("{}", verification);
+            Ok(())
+        }
+        _ => Err(Error::BadSignature(format!("{}: {}", oid, verification))),
+    }
+}
+
+fn verify_commit(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let oid = repo.get_commit_oid(submatches.value_of("COMMIT").unwrap())?;
+    repo.get_commit(&oid)?;
+    print_verification(repo, &oid)
+}
+
+fn verify_tag(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let oid = repo.get_oid(submatches.value_of("TAG").unwrap())?;
+    repo.get_tag(&oid)?;
+    print_verification(repo, &oid)
+}
+
+fn print_commit(
+    oid: &Oid,
+    commit: &base::Commit,
+    refs: Option<&Vec<String>>,
+    format: &pretty::Format,
+    abbrev: usize,
+) -> Result<()> {
+    println!(
+        "{}",
+        pretty::format_commit(format, oid, commit, refs, abbrev)
+    );
     Ok(())
 }
 
-fn log(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let oid = base::get_oid(submatches.value_of("OID").unwrap())?;
-    let refs = data::get_oid_to_refs()?;
-    for oid in base::iter_commits_and_parents([oid].iter().cloned())? {
-        let commit = base::get_commit(&oid)?;
-        print_commit(&oid, commit, refs.get(&oid))?;
+fn parse_count(submatches: &clap::ArgMatches<'_>, name: &str) -> Result<Option<usize>> {
+    match submatches.value_of(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+            Error::InvalidArgument(format!("Invalid number given for {}: {}", name, value))
+        }),
+        None => Ok(None),
+    }
+}
+
+fn get_log_filter(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<history::Filter> {
+    let regex = |name| {
+        submatches
+            .value_of(name)
+            .map(history::parse_regex)
+            .transpose()
+    };
+    let date = |name| submatches.value_of(name).map(date::parse_date).transpose();
+    let pickaxe = if let Some(needle) = submatches.value_of("pickaxe_string") {
+        Some(history::Pickaxe::Occurrences(needle.to_string()))
+    } else {
+        regex("pickaxe_regex")?.map(history::Pickaxe::Lines)
+    };
+    let paths = submatches
+        .values_of("PATHS")
+        .map(|paths| paths.map(|path| tree_path(repo, path)).collect())
+        .transpose()?
+        .unwrap_or_default();
+
+    Ok(history::Filter {
+        paths,
+        follow: submatches.is_present("follow"),
+        author: regex("author")?,
+        grep: regex("grep")?,
+        since: date("since")?,
+        until: date("until")?,
+        pickaxe,
+    })
+}
+
+fn log(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let refs = repo.get_oid_to_refs()?;
+    let tips: Vec<Oid> = if submatches.is_present("all") {
+        let mut tips: Vec<Oid> = refs.keys().cloned().collect();
+        tips.sort();
+        tips
+    } else {
+        vec![repo.get_commit_oid(submatches.value_of("OID").unwrap())?]
+    };
+    let format = if submatches.is_present("oneline") {
+        pretty::Format::Oneline
+    } else if let Some(format) = submatches.value_of("format") {
+        pretty::Format::parse(format)
+    } else {
+        pretty::Format::Medium
+    };
+    let abbrev = parse_count(submatches, "abbrev")?.unwrap_or(10);
+    let skip = parse_count(submatches, "skip")?.unwrap_or(0);
+    let max_count = parse_count(submatches, "max_count")?.unwrap_or(usize::MAX);
+
+    // Commits from several tips need reordering so that no commit is shown
+    // before its descendants, which takes the whole history, as does
+    // reversing it. Otherwise the walk can stop at the last commit shown.
+    let reorder = tips.len() > 1 || submatches.is_present("graph");
+    let limit = match reorder || submatches.is_present("reverse") {
+        true => None,
+        false => Some(skip.saturating_add(max_count)),
+    };
+    let mut oids: Vec<Oid> =
+        history::iter_filtered_commits(repo, &tips, &get_log_filter(repo, submatches)?, limit)?
+            .collect();
+    let mut commits: HashMap<Oid, base::Commit> = HashMap::new();
+    for oid in &oids {
+        commits.insert(oid.clone(), repo.get_commit(oid)?);
+    }
+    // Parents are rewritten past the commits left out by the filter, so that
+    // lines of history join up
+    let parents = match reorder {
+        true => graph::visible_parents(
+            &oids,
+            oids.iter()
+                .map(|oid| (oid.clone(), commits[oid].parents.clone()))
+                .collect(),
+            |oid| Ok(repo.get_commit(oid)?.parents),
+        )?,
+        false => HashMap::new(),
+    };
+
+    if reorder {
+        oids = graph::topo_sort(oids.clone(), &parents);
+    }
+    let mut oids: Vec<Oid> = oids.into_iter().skip(skip).take(max_count).collect();
+    if submatches.is_present("reverse") {
+        oids.reverse();
+    }
+
+    let mut graph = if submatches.is_present("graph") {
+        Some(graph::Graph::new(
+            match submatches.value_of("graph_style") {
+                Some("unicode") => graph::Style::Unicode,
+                _ => graph::Style::Ascii,
+            },
+        ))
+    } else {
+        None
+    };
+    // Commits left out by the count limits are older than all of those
+    // shown, so lines of history through them end there
+    let graph_parents = graph::visible_parents(
+        &oids,
+        oids.iter()
+            .map(|oid| (oid.clone(), parents.get(oid).cloned().unwrap_or_default()))
+            .collect(),
+        |oid| Ok(parents.get(oid).cloned().unwrap_or_default()),
+    )?;
+    for oid in &oids {
+        let commit = &commits[oid];
+        let mut text = pretty::format_commit(&format, oid, commit, refs.get(oid), abbrev);
+        if submatches.is_present("show_signature") {
+            // Shown beneath the "commit" line of the default format, and
+            // above the commit otherwise
+            let verification = repo.verify_object(oid)?.to_string();
+            text = match (&format, text.split_once('\n')) {
+                (pretty::Format::Medium, Some((first, rest))) => {
+                    format!("{}\n{}\n{}", first, verification, rest)
+                }
+                _ => format!("{}\n{}", verification, text),
+            };
+        }
+        match graph.as_mut() {
+            Some(graph) => println!("{}", graph.draw_commit(oid, &graph_parents[oid], &text)),
+            None => println!("{}", text),
+        }
     }
     Ok(())
 }
 
-fn show(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let oid = base::get_oid(submatches.value_of("OID").unwrap())?;
-    let commit = base::get_commit(&oid)?;
-    let oid_to_refs = data::get_oid_to_refs()?;
+fn show(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let oid = repo.get_commit_oid(submatches.value_of("OID").unwrap())?;
+    let commit = repo.get_commit(&oid)?;
+    let oid_to_refs = repo.get_oid_to_refs()?;
     let refs = oid_to_refs.get(&oid);
 
     let base_path = Path::new("").to_path_buf();
-    let parent_tree = base::get_tree(
-        if let Some(parent) = commit.parent.clone() {
-            let commit = base::get_commit(&parent)?;
+    let parent_tree = repo.get_tree(
+        if let Some(parent) = commit.parents.first() {
+            let commit = repo.get_commit(parent)?;
             Some(commit.tree)
         } else {
             None
@@ -168,59 +554,439 @@ fn show(submatches: &clap::ArgMatches<'_>) -> Result<()> {
         .as_ref(),
         base_path.clone(),
     )?;
-    let commit_tree = base::get_tree(Some(&commit.tree), base_path)?;
-    let result = diff::diff_trees(parent_tree, commit_tree)?;
+    let commit_tree = repo.get_tree(Some(&commit.tree), base_path)?;
+    let result = repo.diff_trees(parent_tree, commit_tree)?;
 
-    print_commit(&oid, commit, refs)?;
+    print_commit(&oid, &commit, refs, &pretty::Format::Medium, 10)?;
     io::stdout().flush()?;
-    io::stdout().write_all(&result)
+    io::stdout().write_all(&result)?;
+    Ok(())
 }
 
-fn diff(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let oid = base::get_oid(submatches.value_of("COMMIT").unwrap())?;
-    let commit = base::get_commit(&oid)?;
+fn diff(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let oid = repo.get_commit_oid(submatches.value_of("COMMIT").unwrap())?;
+    let commit = repo.get_commit(&oid)?;
     let base_path = Path::new("").to_path_buf();
-    let result = diff::diff_trees(
-        base::get_tree(Some(&commit.tree), base_path)?,
-        base::get_working_tree()?,
-    )?;
+    let result = repo.diff_working_tree(repo.get_tree(Some(&commit.tree), base_path)?)?;
     io::stdout().flush()?;
-    io::stdout().write_all(&result)
+    io::stdout().write_all(&result)?;
+    Ok(())
 }
 
-fn checkout(submatches: &clap::ArgMatches<'_>) -> Result<()> {
+fn checkout_mode(submatches: &clap::ArgMatches<'_>) -> base::CheckoutMode {
+    if submatches.is_present("force") {
+        base::CheckoutMode::Force
+    } else if submatches.is_present("merge") {
+        base::CheckoutMode::Merge
+    } else {
+        base::CheckoutMode::Safe
+    }
+}
+
+fn print_conflicts(conflicts: &[PathBuf]) {
+    for path in conflicts {
+        println!(
+            "CONFLICT (content): Merge conflict in {}",
+            path.to_string_lossy()
+        );
+    }
+}
+
+fn checkout(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
     let name = submatches.value_of("COMMIT").unwrap();
-    base::checkout(name)
+    print_conflicts(&repo.checkout(name, checkout_mode(submatches))?);
+    Ok(())
 }
 
-fn reset(submatches: &clap::ArgMatches<'_>) -> Result<()> {
+fn reset(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
     let name = submatches.value_of("COMMIT").unwrap();
-    let oid = base::get_oid(name)?;
-    base::reset(oid)
+    let oid = repo.get_commit_oid(name)?;
+    print_conflicts(&repo.reset(oid, checkout_mode(submatches))?);
+    Ok(())
 }
 
-fn tag(submatches: &clap::ArgMatches<'_>) -> Result<()> {
-    let name = submatches.value_of("NAME").unwrap();
-    let oid = base::get_oid(submatches.value_of("OID").unwrap())?;
-    base::create_tag(name, &oid)
+// Match a name against a shell-style pattern, where "*" matches any run of
+// characters and "?" any single character
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(('?', rest)), Some((_, name_rest))) => glob_match(rest, name_rest),
+        (Some((p, rest)), Some((c, name_rest))) if p == c => glob_match(rest, name_rest),
+        _ => false,
+    }
 }
 
-fn branch(submatches: &clap::ArgMatches<'_>) -> Result<()> {
+fn tag(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
     let name = submatches.value_of("NAME");
-    if let Some(name) = name {
-        let start = base::get_oid(submatches.value_of("START").unwrap())?;
-        base::create_branch(name, &start)?;
-        println!("Branch '{}' created at {}", name, &start[..10]);
-    } else {
-        let current = base::get_branch_name()?;
-        for branch in base::iter_branch_names()? {
-            let prefix = if Some(&branch) == current.as_ref() {
-                "*"
-            } else {
-                " "
+    match name {
+        Some(name) if submatches.is_present("delete") => {
+            let oid = repo.delete_tag(name)?;
+            println!("Deleted tag '{}' (was {})", name, &oid[..10]);
+        }
+        Some(name) if !submatches.is_present("list") => {
+            let oid = repo.get_oid(submatches.value_of("OID").unwrap())?;
+            match submatches.value_of("message") {
+                Some(message) => {
+                    repo.create_annotated_tag(name, &oid, message, submatches.is_present("sign"))?;
+                }
+                None => repo.create_tag(name, &oid)?,
+            }
+        }
+        // Without a tag to create, the tags matching the pattern are listed
+        pattern => {
+            let pattern: Vec<char> = pattern.unwrap_or("*").chars().collect();
+            for (refname, _) in repo.iter_refs(Some("refs/tags/"), false)? {
+                let tag = &refname["refs/tags/".len()..];
+                if glob_match(&pattern, &tag.chars().collect::<Vec<char>>()) {
+                    println!("{}", tag);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn branch(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let name = submatches.value_of("NAME");
+    let start = submatches.value_of("START");
+    match name {
+        Some(name) if submatches.is_present("delete") || submatches.is_present("force_delete") => {
+            let oid = repo.delete_branch(name, submatches.is_present("force_delete"))?;
+            println!("Deleted branch {} (was {})", name, &oid[..10]);
+        }
+        Some(name) if submatches.is_present("move") => {
+            // With a single name, the current branch is renamed
+            let (old, new) = match start {
+                Some(new) => (name.to_string(), new),
+                None => (
+                    repo.get_branch_name()?.ok_or_else(|| {
+                        Error::InvalidArgument("HEAD is not on a branch to rename".to_string())
+                    })?,
+                    name,
+                ),
             };
+            repo.rename_branch(&old, new)?;
+            println!("Branch '{}' renamed to '{}'", old, new);
+        }
+        Some(name) => {
+            let start = repo.get_commit_oid(start.unwrap_or("HEAD"))?;
+            repo.create_branch(name, &start)?;
+            println!("Branch '{}' created at {}", name, &start[..10]);
+        }
+        None => list_branches(repo, submatches)?,
+    }
+    Ok(())
+}
+
+fn list_branches(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    // Commits reachable from the --merged or --no-merged commit, and whether
+    // branches must be among them or not
+    let merged_filter = match (
+        submatches.is_present("merged"),
+        submatches.is_present("no_merged"),
+    ) {
+        (false, false) => None,
+        (merged, _) => {
+            let name = submatches
+                .value_of(if merged { "merged" } else { "no_merged" })
+                .unwrap_or("HEAD");
+            let oid = repo.get_commit_oid(name)?;
+            let reachable: HashSet<Oid> = repo
+                .iter_commits_and_parents(vec![oid].into_iter())?
+                .collect();
+            Some((reachable, merged))
+        }
+    };
+    let contains = match submatches.value_of("contains") {
+        Some(name) => Some(repo.get_commit_oid(name)?),
+        None => None,
+    };
+
+    let current = repo.get_branch_name()?;
+    let mut branches = Vec::new();
+    for branch in repo.iter_branch_names()? {
+        let oid = repo.get_oid(&format!("refs/heads/{}", branch))?;
+        if let Some((reachable, merged)) = &merged_filter {
+            if reachable.contains(&oid) != *merged {
+                continue;
+            }
+        }
+        if let Some(contains) = &contains {
+            if !repo.is_ancestor(contains, &oid)? {
+                continue;
+            }
+        }
+        branches.push((branch, oid));
+    }
+
+    let width = branches
+        .iter()
+        .map(|(branch, _)| branch.len())
+        .max()
+        .unwrap_or(0);
+    for (branch, oid) in branches {
+        let prefix = if Some(&branch) == current.as_ref() {
+            "*"
+        } else {
+            " "
+        };
+        if submatches.is_present("verbose") {
+            let subject = repo.get_commit(&oid)?.subject().to_string();
+            println!(
+                "{} {:width$} {} {}",
+                prefix,
+                branch,
+                &oid[..10],
+                subject,
+                width = width
+            );
+        } else {
             println!("{} {}", prefix, branch);
         }
     }
     Ok(())
 }
+
+fn format_patch(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let (since, until) = repo.get_range(submatches.value_of("RANGE").unwrap())?;
+    let mut oids: Vec<Oid> = repo.iter_commits_in_range(&since, &until)?.collect();
+    // Patches are numbered in the order they need to be applied
+    oids.reverse();
+
+    let out_dir = Path::new(submatches.value_of("output-directory").unwrap_or("."));
+    for (i, oid) in oids.iter().enumerate() {
+        let commit = repo.get_commit(oid)?;
+        let patch = patch::format_patch(repo, oid, &commit, i + 1, oids.len())?;
+        if submatches.is_present("stdout") {
+            io::stdout().write_all(&patch)?;
+        } else {
+            fs::create_dir_all(out_dir)?;
+            let path = out_dir.join(patch::patch_filename(i + 1, &commit));
+            fs::write(&path, patch)?;
+            println!("{}", path.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+fn am(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let head_tree = match repo.get_ref("HEAD", true)?.value {
+        Some(head) => Some(repo.get_commit(&head)?.tree),
+        None => None,
+    };
+    let changes = diff::iter_changed_files(
+        repo.get_tree(head_tree.as_ref(), PathBuf::new())?,
+        repo.get_working_tree()?,
+    )?;
+    let dirty: Vec<PathBuf> = changes.map(|(path, _)| path).collect();
+    if !dirty.is_empty() {
+        return Err(Error::DirtyWorktree(dirty));
+    }
+
+    for mbox in submatches.values_of("MBOX").unwrap() {
+        for mail in patch::parse_mailbox(&fs::read(mbox)?)? {
+            println!("Applying: {}", mail.subject);
+            patch::apply_mail(repo, &mail)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_line_range(range: &str) -> Result<(usize, usize)> {
+    let bad_range = || Error::InvalidArgument(format!("Invalid line range: {}", range));
+    let (start, end) = range.split_once(',').ok_or_else(bad_range)?;
+    // Lines are numbered from 1, and a range covers at least one line
+    let start: usize = match start.parse() {
+        Ok(start) if start > 0 => start,
+        _ => return Err(bad_range()),
+    };
+    let end: usize = match end.strip_prefix('+') {
+        // "<start>," runs to the end of the file
+        None if end.is_empty() => usize::MAX,
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count > 0 => start
+                .checked_add(count)
+                .and_then(|end| end.checked_sub(1))
+                .ok_or_else(bad_range)?,
+            _ => return Err(bad_range()),
+        },
+        None => match end.parse() {
+            Ok(end) if end >= start => end,
+            _ => return Err(bad_range()),
+        },
+    };
+    Ok((start, end))
+}
+
+fn print_blame_porcelain(repo: &Repository, lines: &[blame::BlameLine]) -> Result<()> {
+    let mut seen: HashSet<&Oid> = HashSet::new();
+    let mut stdout = io::stdout();
+    for (i, line) in lines.iter().enumerate() {
+        // The size of each group of consecutive lines from the same commit is
+        // given on the first line of the group
+        let continues_group =
+            i > 0 && lines[i - 1].oid == line.oid && lines[i - 1].orig_line + 1 == line.orig_line;
+        if continues_group {
+            writeln!(
+                stdout,
+                "{} {} {}",
+                line.oid, line.orig_line, line.final_line
+            )?;
+        } else {
+            let group_len = lines[i..]
+                .iter()
+                .zip(line.orig_line..)
+                .take_while(|(l, orig_line)| l.oid == line.oid && l.orig_line == *orig_line)
+                .count();
+            writeln!(
+                stdout,
+                "{} {} {} {}",
+                line.oid, line.orig_line, line.final_line, group_len
+            )?;
+        }
+
+        if seen.insert(&line.oid) {
+            let commit = repo.get_commit(&line.oid)?;
+            for (role, signature) in &[("author", &commit.author), ("committer", &commit.committer)]
+            {
+                if let Some(signature) = signature {
+                    writeln!(stdout, "{} {}", role, signature.name)?;
+                    writeln!(stdout, "{}-mail <{}>", role, signature.email)?;
+                    writeln!(stdout, "{}-time {}", role, signature.time.timestamp())?;
+                    writeln!(stdout, "{}-tz {}", role, signature.time.format("%z"))?;
+                }
+            }
+            writeln!(stdout, "summary {}", commit.subject())?;
+            writeln!(stdout, "filename {}", line.path.to_string_lossy())?;
+        }
+
+        stdout.write_all(b"\t")?;
+        stdout.write_all(&line.content)?;
+        if !line.content.ends_with(b"\n") {
+            writeln!(stdout)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_blame(repo: &Repository, lines: &[blame::BlameLine], path: &Path) -> Result<()> {
+    let mut authors: HashMap<&Oid, (String, String)> = HashMap::new();
+    for line in lines {
+        if !authors.contains_key(&line.oid) {
+            let commit = repo.get_commit(&line.oid)?;
+            let author = match commit.author.or(commit.committer) {
+                Some(author) => (
+                    author.name,
+                    author.time.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+                ),
+                None => ("unknown".to_string(), "".to_string()),
+            };
+            authors.insert(&line.oid, author);
+        }
+    }
+
+    // The originating file is only shown if lines came from another path
+    let show_path = lines.iter().any(|line| line.path != path);
+    let path_width = lines
+        .iter()
+        .map(|l| l.path.to_string_lossy().len())
+        .max()
+        .unwrap_or(0);
+    let author_width = authors
+        .values()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let line_width = lines
+        .last()
+        .map(|l| l.final_line.to_string().len())
+        .unwrap_or(0);
+
+    let mut stdout = io::stdout();
+    for line in lines {
+        let (author, date) = &authors[&line.oid];
+        write!(stdout, "{}", &line.oid[..10])?;
+        if show_path {
+            write!(
+                stdout,
+                " {:width$}",
+                line.path.to_string_lossy(),
+                width = path_width
+            )?;
+        }
+        write!(
+            stdout,
+            " ({:author_width$} {} {:>line_width$}) ",
+            author,
+            date,
+            line.final_line,
+            author_width = author_width,
+            line_width = line_width
+        )?;
+        stdout.write_all(&line.content)?;
+        if !line.content.ends_with(b"\n") {
+            writeln!(stdout)?;
+        }
+    }
+    Ok(())
+}
+
+fn blame(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let oid = repo.get_commit_oid(submatches.value_of("REV").unwrap())?;
+    let path = &tree_path(repo, submatches.value_of("FILE").unwrap())?;
+    let range = match submatches.value_of("lines") {
+        Some(range) => Some(parse_line_range(range)?),
+        None => None,
+    };
+
+    let lines = blame::blame(repo, &oid, path, range)?;
+    if submatches.is_present("porcelain") {
+        print_blame_porcelain(repo, &lines)
+    } else {
+        print_blame(repo, &lines, path)
+    }
+}
+
+fn export_html(repo: &Repository, submatches: &clap::ArgMatches<'_>) -> Result<()> {
+    let dir = Path::new(submatches.value_of("DIR").unwrap());
+    let pages = html::export(repo, dir)?;
+    println!("Wrote {} pages to {}", pages, dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_line_range_accepts_end_or_count() {
+        assert_eq!(parse_line_range("3,7").unwrap(), (3, 7));
+        assert_eq!(parse_line_range("3,+1").unwrap(), (3, 3));
+        assert_eq!(parse_line_range("3,+5").unwrap(), (3, 7));
+        assert_eq!(parse_line_range("3,3").unwrap(), (3, 3));
+        assert_eq!(parse_line_range("3,").unwrap(), (3, usize::MAX));
+    }
+
+    #[test]
+    fn parse_line_range_rejects_empty_ranges() {
+        for range in &["0,5", "0,+0", "1,+0", "3", "a,4", "2,+x", "5,3", "0,", ","] {
+            assert!(
+                matches!(parse_line_range(range), Err(Error::InvalidArgument(_))),
+                "{}",
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn parse_line_range_rejects_overflowing_counts() {
+        let range = format!("2,+{}", usize::MAX);
+        assert!(matches!(
+            parse_line_range(&range),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
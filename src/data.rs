@@ -1,20 +1,20 @@
+use crate::error::{Error, Result};
+use crate::repository::Repository;
 use sha1::{Digest, Sha1};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
-use std::fs;
-use std::io::{Error, ErrorKind, Result};
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-
-pub const GIT_DIR: &str = ".gitox";
-const OBJECT_DIR: &str = ".gitox/objects";
-const REF_DIR: &str = ".gitox/refs";
+use tempfile::NamedTempFile;
 
 #[derive(Debug, PartialEq)]
 pub enum ObjectType {
     Blob,
     Tree,
     Commit,
+    Tag,
 }
 
 impl std::fmt::Display for ObjectType {
@@ -26,6 +26,7 @@ impl std::fmt::Display for ObjectType {
                 ObjectType::Blob => "blob",
                 ObjectType::Tree => "tree",
                 ObjectType::Commit => "commit",
+                ObjectType::Tag => "tag",
             }
         )
     }
@@ -38,10 +39,8 @@ impl FromStr for ObjectType {
             "blob" => Ok(ObjectType::Blob),
             "tree" => Ok(ObjectType::Tree),
             "commit" => Ok(ObjectType::Commit),
-            _ => Err(Error::new(
-                ErrorKind::Other,
-                "Parsed string cannot represent a known object type",
-            )),
+            "tag" => Ok(ObjectType::Tag),
+            _ => Err(Error::CorruptObject(format!("Unknown object type: {}", s))),
         }
     }
 }
@@ -49,7 +48,13 @@ impl FromStr for ObjectType {
 impl TryFrom<&[u8]> for ObjectType {
     type Error = Error;
     fn try_from(bytes: &[u8]) -> Result<Self> {
-        Self::from_str(&String::from_utf8(bytes.to_vec()).unwrap())
+        match std::str::from_utf8(bytes) {
+            Ok(s) => Self::from_str(s),
+            Err(_) => Err(Error::CorruptObject(format!(
+                "Unknown object type: {}",
+                String::from_utf8_lossy(bytes)
+            ))),
+        }
     }
 }
 
@@ -61,46 +66,97 @@ pub struct Object {
 
 pub type Oid = String;
 
-pub fn init() -> Result<()> {
-    fs::create_dir_all(GIT_DIR)?;
-    fs::create_dir_all(OBJECT_DIR)?;
-    fs::create_dir_all(REF_DIR)?;
-    Ok(())
+/// The object ID of all zeroes, which stands for no object at all, e.g. the
+/// old value of a ref which is being created.
+pub const NULL_OID: &str = "0000000000000000000000000000000000000000";
+
+/// Whether a string is a full object ID.
+pub fn is_oid(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
 }
 
-pub fn hash_object(contents: &[u8], t: ObjectType) -> Result<Oid> {
-    // Format of an object is its type, null byte then the contents
+// Format of an object is its type, null byte then the contents
+fn serialize_object(contents: &[u8], t: ObjectType) -> Vec<u8> {
     let t_str = format!("{}", t);
-    let data = [t_str.as_bytes(), b"\x00", contents].concat();
-    let hash = Sha1::digest(&data);
-    let oid = format!("{:x}", hash);
+    [t_str.as_bytes(), b"\x00", contents].concat()
+}
 
-    fs::write(format!("{}/{oid}", OBJECT_DIR, oid = oid), data)?;
-    Ok(oid)
+/// The ID an object would be stored under, without storing it.
+pub fn compute_oid(contents: &[u8], t: ObjectType) -> Oid {
+    format!("{:x}", Sha1::digest(&serialize_object(contents, t)))
 }
 
-pub fn get_object(oid: &Oid, expected: Option<ObjectType>) -> Result<Object> {
-    let raw = fs::read(format!("{}/{oid}", OBJECT_DIR, oid = oid))?;
+impl Repository {
+    /// Store an object, returning its ID.
+    pub fn hash_object(&self, contents: &[u8], t: ObjectType) -> Result<Oid> {
+        let data = serialize_object(contents, t);
+        let oid = format!("{:x}", Sha1::digest(&data));
+
+        // Objects are named by their contents, so one which is already stored
+        // doesn't need writing again. Otherwise the object is written to a
+        // temporary file first and renamed into place, so that a crash never
+        // leaves a truncated object behind.
+        let path = self.object_path(&oid);
+        if !path.exists() {
+            let mut file = NamedTempFile::new_in(self.object_path(""))?;
+            file.write_all(&data)?;
+            file.persist(&path).map_err(|e| e.error)?;
+        }
+        Ok(oid)
+    }
 
-    // Object type is the first byte slice before a null byte
-    let fields: Vec<&[u8]> = raw.splitn(2, |c| *c == b'\0').collect();
-    let t_bytes = fields.get(0).unwrap();
-    let contents = fields.get(1).unwrap();
-    let t = ObjectType::try_from(*t_bytes)?;
+    /// List the IDs of stored objects which begin with the given prefix.
+    pub fn find_objects(&self, prefix: &str) -> Result<Vec<Oid>> {
+        let mut oids = Vec::new();
+        for entry in fs::read_dir(self.object_path(""))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) && is_oid(&name) {
+                oids.push(name);
+            }
+        }
+        oids.sort();
+        Ok(oids)
+    }
 
-    if let Some(expected) = expected {
-        if expected != t {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Expected {:?}, retrieved {:?} object", expected, t),
-            ));
+    pub fn get_object(&self, oid: &Oid, expected: Option<ObjectType>) -> Result<Object> {
+        if !is_oid(oid) {
+            return Err(Error::ObjectNotFound(oid.clone()));
         }
-    };
+        let raw = match fs::read(self.object_path(oid)) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::ObjectNotFound(oid.clone()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Object type is the first byte slice before a null byte
+        let (t_bytes, contents) = match raw.iter().position(|c| *c == b'\0') {
+            Some(i) => (&raw[..i], &raw[i + 1..]),
+            None => {
+                return Err(Error::CorruptObject(format!(
+                    "{} has no object type header",
+                    oid
+                )))
+            }
+        };
+        let t = ObjectType::try_from(t_bytes)?;
+
+        if let Some(expected) = expected {
+            if expected != t {
+                return Err(Error::WrongObjectType {
+                    oid: oid.clone(),
+                    expected,
+                    actual: t,
+                });
+            }
+        };
 
-    Ok(Object {
-        t: t,
-        contents: contents.to_vec(),
-    })
+        Ok(Object {
+            t,
+            contents: contents.to_vec(),
+        })
+    }
 }
 
 pub struct RefValue {
@@ -108,105 +164,727 @@ pub struct RefValue {
     pub value: Option<String>,
 }
 
-fn get_ref_internal(ref_: &str, deref: bool) -> Result<(String, RefValue)> {
-    let ref_object = format!("{}/{}", GIT_DIR, ref_);
-    let ref_path = Path::new(&ref_object);
-    let mut symbolic = false;
-    let ref_value = match ref_path.exists() {
-        false => None,
-        true => Some(Oid::from_utf8_lossy(&fs::read(ref_path)?).to_string()),
-    };
-    let mut value = ref_value.clone();
-
-    if let Some(ref_value) = ref_value {
-        if let Some(sym_ref) = ref_value.strip_prefix("ref: ") {
-            value = Some(sym_ref.to_string());
-            symbolic = true;
-            if deref {
-                // Recursively dereference the symbolic ref
-                return get_ref_internal(sym_ref, true);
-            }
+// Add the names of the refs within a directory of refs called `name`
+fn append_ref_paths(mut v: Vec<String>, dir: &Path, name: &str) -> Result<Vec<String>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let entry_name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+        if entry.path().is_dir() {
+            v = append_ref_paths(v, entry.path().as_path(), &entry_name)?;
+        } else if !entry_name.ends_with(LOCK_SUFFIX) {
+            v.push(entry_name);
         }
     }
+    Ok(v)
+}
 
-    Ok((
-        ref_.to_string(),
-        RefValue {
-            symbolic,
-            value: value,
-        },
-    ))
+// Refs outside of refs/ (e.g. HEAD) are named in capitals, which keeps them
+// apart from the other files in the git directory
+fn is_root_ref(ref_: &str) -> bool {
+    !ref_.is_empty() && ref_.chars().all(|c| c.is_ascii_uppercase() || c == '_')
 }
 
-pub fn update_ref(ref_: &str, value: RefValue, deref: bool) -> Result<()> {
-    let ref_ = get_ref_internal(ref_, deref).map(|(ref_, _)| ref_)?;
+// Refs are stored as files beneath the git directory, so the refs which are
+// read must not be able to reach outside of refs/, or be mistaken for another
+// ref's lock file. Refs which are written must also follow `check_ref_format`.
+fn check_ref_path(ref_: &str) -> Result<()> {
+    let escapes = ref_.ends_with(LOCK_SUFFIX)
+        || ref_
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..");
+    match is_root_ref(ref_) || (ref_.starts_with("refs/") && !escapes) {
+        true => Ok(()),
+        false => Err(Error::BadRefName(ref_.to_string())),
+    }
+}
 
-    let raw_value = value
-        .value
-        .expect("Cannot update a reference with an empty value");
-    let raw_value: String = match value.symbolic {
-        true => "ref: ".to_string() + &raw_value,
-        false => raw_value,
-    };
+/// Check a ref name against Git's rules, which keep names unambiguous in
+/// revision syntax and safe to store as files. Components are separated by
+/// slashes, and names must have at least two of them unless
+/// `allow_onelevel` is set. Names must not:
+///
+/// - begin or end with a slash, or contain consecutive slashes
+/// - have components beginning with a dot or ending with ".lock"
+/// - contain "..", "@{", or a backslash
+/// - contain control characters, spaces, or any of `~^:?*[`
+/// - end with a dot, or be "@" alone
+pub fn check_ref_format(name: &str, allow_onelevel: bool) -> Result<()> {
+    let bad_component =
+        |part: &str| part.is_empty() || part.starts_with('.') || part.ends_with(LOCK_SUFFIX);
+    let bad_char = |c: char| c.is_ascii_control() || " ~^:?*[\\".contains(c);
+    let valid = name != "@"
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.chars().any(bad_char)
+        && !name.split('/').any(bad_component)
+        && (allow_onelevel || name.contains('/'));
+    match valid {
+        true => Ok(()),
+        false => Err(Error::BadRefName(name.to_string())),
+    }
+}
 
-    let ref_object = format!("{}/{}", GIT_DIR, ref_);
-    let ref_path = Path::new(&ref_object);
-    fs::create_dir_all(ref_path.parent().unwrap())?;
-    fs::write(ref_object, raw_value)
+// Refs can only be written outside of refs/ if they are root refs like HEAD
+fn check_ref_write(ref_: &str) -> Result<()> {
+    match is_root_ref(ref_) {
+        true => Ok(()),
+        false if ref_.starts_with("refs/") => check_ref_format(ref_, false),
+        false => Err(Error::BadRefName(ref_.to_string())),
+    }
 }
 
-pub fn get_ref(ref_: &str, deref: bool) -> Result<RefValue> {
-    get_ref_internal(ref_, deref).map(|(_, value)| value)
+const LOCK_SUFFIX: &str = ".lock";
+
+/// File beneath the git directory holding refs packed together by
+/// `pack_refs`, one `<oid> <ref>` per line. Loose ref files take precedence
+/// over the refs in it.
+const PACKED_REFS: &str = "packed-refs";
+
+/// An exclusive lock on a ref, held for as long as `<ref>.lock` exists. The
+/// new value is written to the lock file, which then replaces the ref in a
+/// single rename so that readers never see a partially written ref. The lock
+/// is released without changing the ref if it is dropped before committing.
+pub(crate) struct RefLock {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
 }
 
-fn append_ref_paths(mut v: Vec<String>, dir: &Path) -> Result<Vec<String>> {
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
-        if entry.path().is_dir() {
-            v = append_ref_paths(v, entry.path().as_path())?;
-        } else {
-            v.push(
-                entry
-                    .path()
-                    .strip_prefix(GIT_DIR)
-                    .unwrap()
-                    .to_string_lossy()
-                    .into_owned(),
-            );
+impl RefLock {
+    pub(crate) fn acquire(path: PathBuf) -> Result<RefLock> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(LOCK_SUFFIX);
+        let lock_path = PathBuf::from(lock_path);
+        let file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(Error::Locked(lock_path)),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(RefLock {
+            path,
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    /// Write new contents for the locked ref without replacing it yet.
+    pub(crate) fn write(&mut self, contents: &str) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the locked ref with new contents, releasing the lock.
+    pub(crate) fn commit(mut self, contents: &str) -> Result<()> {
+        self.write(contents)?;
+        self.finish()
+    }
+
+    /// Replace the locked ref with the contents already written.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        fs::rename(&self.lock_path, &self.path)?;
+        // The lock file is gone, so there is nothing left for drop to remove
+        // (and another process may have taken the lock again by then)
+        self.file = None;
+        Ok(())
+    }
+
+    /// Remove the locked ref, releasing the lock.
+    pub(crate) fn delete(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for RefLock {
+    fn drop(&mut self) {
+        // Only an uncommitted lock still has its file open
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
         }
     }
-    Ok(v)
 }
 
-pub fn iter_refs(
-    prefix: Option<&str>,
+fn write_packed_refs(lock: RefLock, refs: &BTreeMap<String, Oid>) -> Result<()> {
+    lock.commit(&packed_refs_contents(refs))
+}
+
+fn packed_refs_contents(refs: &BTreeMap<String, Oid>) -> String {
+    let mut contents = String::from("# pack-refs with: sorted\n");
+    for (ref_, oid) in refs {
+        contents += &format!("{} {}\n", oid, ref_);
+    }
+    contents
+}
+
+/// The value a ref must have for a transaction to go ahead.
+#[derive(Debug, PartialEq)]
+pub enum Expected {
+    /// Any value, or none at all
+    Any,
+    /// The ref must not exist yet
+    Missing,
+    /// The ref must have exactly this value
+    Value(String),
+}
+
+struct RefUpdate {
+    ref_: String,
     deref: bool,
-) -> Result<impl Iterator<Item = (String, RefValue)>> {
-    let mut refnames: Vec<String> = Vec::new();
-    let mut refs = Vec::new();
-    refnames.push("HEAD".to_string());
-    refnames = append_ref_paths(refnames, Path::new(REF_DIR))?;
-
-    for refname in refnames {
-        if let Some(prefix) = prefix {
-            if !refname.starts_with(prefix) {
+    // None when the ref is only being verified
+    change: Option<Change>,
+    old: Expected,
+}
+
+enum Change {
+    Update(RefValue),
+    Delete,
+}
+
+/// A queue of ref updates, each of which can require the ref to have an
+/// expected value first. Committing locks every ref involved and checks all
+/// of the expected values before changing any of the refs, so that if one
+/// update can't be made then none are. Each change is recorded in the ref's
+/// reflog with the reason given for the transaction.
+pub struct Transaction<'a> {
+    repo: &'a Repository,
+    reason: String,
+    updates: Vec<RefUpdate>,
+}
+
+// A ref update resolved to the ref which will actually be changed
+struct PreparedUpdate {
+    ref_: String,
+    // None when the ref is only being verified, or Some(None) to delete it
+    contents: Option<Option<String>>,
+    old: Expected,
+}
+
+impl Transaction<'_> {
+    /// Set a ref to a new value, creating it if need be.
+    pub fn update(&mut self, ref_: &str, value: RefValue, deref: bool, old: Expected) -> &mut Self {
+        self.push(ref_, deref, Some(Change::Update(value)), old)
+    }
+
+    /// Remove a ref.
+    pub fn delete(&mut self, ref_: &str, deref: bool, old: Expected) -> &mut Self {
+        self.push(ref_, deref, Some(Change::Delete), old)
+    }
+
+    /// Check that a ref has a value without changing it.
+    pub fn verify(&mut self, ref_: &str, old: Expected) -> &mut Self {
+        self.push(ref_, true, None, old)
+    }
+
+    fn push(
+        &mut self,
+        ref_: &str,
+        deref: bool,
+        change: Option<Change>,
+        old: Expected,
+    ) -> &mut Self {
+        self.updates.push(RefUpdate {
+            ref_: ref_.to_string(),
+            deref,
+            change,
+            old,
+        });
+        self
+    }
+
+    fn prepare(&self, update: RefUpdate) -> Result<PreparedUpdate> {
+        let ref_ = self.repo.get_ref_internal(&update.ref_, update.deref)?.0;
+        if update.change.is_some() {
+            check_ref_write(&ref_)?;
+        }
+        let contents = match update.change {
+            Some(Change::Update(RefValue { value: None, .. })) => {
+                return Err(Error::InvalidArgument(format!(
+                    "Cannot update {} with an empty value",
+                    ref_
+                )))
+            }
+            Some(Change::Update(RefValue {
+                symbolic: true,
+                value: Some(value),
+            })) => {
+                check_ref_write(&value)?;
+                Some(Some("ref: ".to_string() + &value))
+            }
+            Some(Change::Update(RefValue {
+                symbolic: false,
+                value: Some(value),
+            })) => Some(Some(value)),
+            Some(Change::Delete) => Some(None),
+            None => None,
+        };
+        Ok(PreparedUpdate {
+            ref_,
+            contents,
+            old: update.old,
+        })
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        let repo = self.repo;
+        let mut prepared: Vec<PreparedUpdate> = Vec::new();
+        for update in std::mem::take(&mut self.updates) {
+            let update = self.prepare(update)?;
+            if prepared.iter().any(|other| other.ref_ == update.ref_) {
+                return Err(Error::InvalidArgument(format!(
+                    "Multiple updates for ref {} in one transaction",
+                    update.ref_
+                )));
+            }
+            prepared.push(update);
+        }
+
+        // Every lock is taken before anything is checked or changed. Any
+        // locks already held are released if a later one can't be taken.
+        let mut locks = Vec::new();
+        for update in &prepared {
+            locks.push(RefLock::acquire(repo.ref_path(&update.ref_))?);
+        }
+        // Deleted refs must be removed from the packed refs as well
+        let deletes_ref = |update: &PreparedUpdate| update.contents == Some(None);
+        let packed_lock = match prepared.iter().any(deletes_ref) {
+            true => Some(RefLock::acquire(repo.ref_path(PACKED_REFS))?),
+            false => None,
+        };
+        // Old values are only read once the locks are held, so that they
+        // can't change before the update
+        for update in &prepared {
+            let actual = repo.get_ref(&update.ref_, false)?.value;
+            let matches = match &update.old {
+                Expected::Any => true,
+                Expected::Missing => actual.is_none(),
+                Expected::Value(value) => actual.as_ref() == Some(value),
+            };
+            if !matches {
+                return Err(Error::StaleRef {
+                    name: update.ref_.clone(),
+                    expected: match &update.old {
+                        Expected::Value(value) => Some(value.clone()),
+                        _ => None,
+                    },
+                    actual,
+                });
+            }
+        }
+
+        // Everything is written out before any ref is replaced, so that a
+        // failure part way through leaves every ref as it was
+        let packed_lock = match packed_lock {
+            Some(mut packed_lock) => {
+                let mut packed = repo.read_packed_refs()?;
+                let count = packed.len();
+                for update in prepared.iter().filter(|update| deletes_ref(update)) {
+                    packed.remove(&update.ref_);
+                }
+                match packed.len() != count {
+                    true => {
+                        packed_lock.write(&packed_refs_contents(&packed))?;
+                        Some(packed_lock)
+                    }
+                    false => None,
+                }
+            }
+            None => None,
+        };
+        for (lock, update) in locks.iter_mut().zip(&prepared) {
+            if let Some(Some(contents)) = &update.contents {
+                lock.write(contents)?;
+            }
+        }
+
+        // Updating the branch checked out also moves HEAD, so is logged for
+        // HEAD as well, unless HEAD is itself being changed
+        let head = repo.get_ref("HEAD", false)?;
+        let head_branch = match head.symbolic {
+            true => head.value,
+            false => None,
+        };
+        let head_changed = prepared.iter().any(|update| update.ref_ == "HEAD");
+
+        // Old values are logged as they were before the transaction, rather
+        // than after the updates made before them (e.g. HEAD after the branch
+        // it points to has been renamed away)
+        let olds = prepared
+            .iter()
+            .map(|update| Ok(repo.get_ref(&update.ref_, true)?.value))
+            .collect::<Result<Vec<Option<Oid>>>>()?;
+
+        // Loose refs are replaced first and the packed refs last, and only
+        // then are deleted loose refs removed, so that removing a loose ref
+        // never uncovers an old packed value
+        let mut deleted = Vec::new();
+        for ((lock, update), old) in locks.into_iter().zip(prepared).zip(olds) {
+            match update.contents {
+                Some(Some(_)) => {
+                    lock.finish()?;
+                    let new = repo.get_ref(&update.ref_, true)?.value;
+                    // Nothing is logged while both sides are unborn, e.g.
+                    // when HEAD is first pointed at a branch
+                    if old.is_some() || new.is_some() {
+                        repo.append_reflog(
+                            &update.ref_,
+                            old.as_deref(),
+                            new.as_deref(),
+                            &self.reason,
+                        )?;
+                        if !head_changed && head_branch.as_ref() == Some(&update.ref_) {
+                            repo.append_reflog(
+                                "HEAD",
+                                old.as_deref(),
+                                new.as_deref(),
+                                &self.reason,
+                            )?;
+                        }
+                    }
+                }
+                Some(None) => deleted.push((lock, update.ref_)),
+                None => {}
+            }
+        }
+        if let Some(packed_lock) = packed_lock {
+            packed_lock.finish()?;
+        }
+        for (lock, ref_) in deleted {
+            lock.delete()?;
+            repo.delete_reflog(&ref_)?;
+        }
+        Ok(())
+    }
+}
+
+impl Repository {
+    fn get_ref_internal(&self, ref_: &str, deref: bool) -> Result<(String, RefValue)> {
+        check_ref_path(ref_)?;
+        let ref_path = self.ref_path(ref_);
+        let mut symbolic = false;
+        let ref_value = match ref_path.exists() {
+            false => self.read_packed_refs()?.remove(ref_),
+            true => Some(Oid::from_utf8_lossy(&fs::read(ref_path)?).to_string()),
+        };
+        let mut value = ref_value.clone();
+
+        if let Some(ref_value) = ref_value {
+            if let Some(sym_ref) = ref_value.strip_prefix("ref: ") {
+                value = Some(sym_ref.to_string());
+                symbolic = true;
+                if deref {
+                    // Recursively dereference the symbolic ref
+                    return self.get_ref_internal(sym_ref, true);
+                }
+            }
+        }
+
+        Ok((ref_.to_string(), RefValue { symbolic, value }))
+    }
+
+    /// Set a ref to a new value, recording the reason for the change in its
+    /// reflog.
+    pub fn update_ref(&self, ref_: &str, value: RefValue, deref: bool, reason: &str) -> Result<()> {
+        let mut transaction = self.transaction(reason);
+        transaction.update(ref_, value, deref, Expected::Any);
+        transaction.commit()
+    }
+
+    /// Update a ref only if it still has the value `old` (or doesn't exist,
+    /// when `old` is `None`), so that a concurrent update isn't lost.
+    pub fn compare_and_swap_ref(
+        &self,
+        ref_: &str,
+        old: Option<&str>,
+        value: RefValue,
+        deref: bool,
+        reason: &str,
+    ) -> Result<()> {
+        let old = match old {
+            Some(oid) => Expected::Value(oid.to_string()),
+            None => Expected::Missing,
+        };
+        let mut transaction = self.transaction(reason);
+        transaction.update(ref_, value, deref, old);
+        transaction.commit()
+    }
+
+    /// Start a set of ref updates which are made all together, or not at all,
+    /// for the given reason.
+    pub fn transaction(&self, reason: &str) -> Transaction<'_> {
+        Transaction {
+            repo: self,
+            reason: reason.to_string(),
+            updates: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self, ref_: &str, deref: bool) -> Result<RefValue> {
+        self.get_ref_internal(ref_, deref).map(|(_, value)| value)
+    }
+
+    pub fn iter_refs(
+        &self,
+        prefix: Option<&str>,
+        deref: bool,
+    ) -> Result<impl Iterator<Item = (String, RefValue)>> {
+        let mut refnames: Vec<String> = Vec::new();
+        let mut refs = Vec::new();
+        refnames = append_ref_paths(refnames, &self.ref_path("refs"), "refs")?;
+        // The packed refs are read just once, rather than for every ref
+        let mut packed = self.read_packed_refs()?;
+        refnames.extend(packed.keys().cloned());
+        refnames.sort();
+        refnames.dedup();
+        refnames.insert(0, "HEAD".to_string());
+
+        for refname in refnames {
+            if let Some(prefix) = prefix {
+                if !refname.starts_with(prefix) {
+                    continue;
+                }
+            }
+            let value = match (self.ref_path(&refname).exists(), packed.remove(&refname)) {
+                (false, Some(oid)) => RefValue {
+                    symbolic: false,
+                    value: Some(oid),
+                },
+                _ => self.get_ref(&refname, deref)?,
+            };
+            refs.push((refname, value));
+        }
+
+        Ok(refs.into_iter())
+    }
+
+    fn read_packed_refs(&self) -> Result<BTreeMap<String, Oid>> {
+        let contents = match fs::read_to_string(self.ref_path(PACKED_REFS)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut refs = BTreeMap::new();
+        for line in contents.lines() {
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
+            let (oid, ref_) = line.split_once(' ').ok_or_else(|| {
+                Error::CorruptObject(format!("Corrupted line in packed-refs: {}", line))
+            })?;
+            refs.insert(ref_.to_string(), oid.to_string());
         }
-        let value = get_ref(&refname, deref)?;
-        refs.push((refname, value));
+        Ok(refs)
     }
 
-    Ok(refs.into_iter())
+    /// Move refs into the packed-refs file, removing their loose files, so
+    /// that they can all be read at once. Only tags are packed unless `all`
+    /// is set, since branches are usually updated too often to be worth it.
+    /// Returns the number of refs packed.
+    pub fn pack_refs(&self, all: bool) -> Result<usize> {
+        let packed_lock = RefLock::acquire(self.ref_path(PACKED_REFS))?;
+        let mut packed = self.read_packed_refs()?;
+
+        let prefix = if all { "refs/" } else { "refs/tags/" };
+        let mut locks = Vec::new();
+        for ref_ in append_ref_paths(Vec::new(), &self.ref_path("refs"), "refs")? {
+            if !ref_.starts_with(prefix) {
+                continue;
+            }
+            // Each loose ref is locked so that it can't be updated between
+            // being packed and removed
+            let lock = RefLock::acquire(self.ref_path(&ref_))?;
+            let value = self.get_ref(&ref_, false)?;
+            // Symbolic refs are always left loose
+            if let RefValue {
+                symbolic: false,
+                value: Some(oid),
+            } = value
+            {
+                packed.insert(ref_, oid);
+                locks.push(lock);
+            }
+        }
+
+        write_packed_refs(packed_lock, &packed)?;
+        let count = locks.len();
+        for lock in locks {
+            lock.delete()?;
+        }
+        Ok(count)
+    }
+
+    // Construct a lookup from OIDs to refs which point to it in some way,
+    // with annotated tags looked up by the object they tag
+    pub fn get_oid_to_refs(&self) -> Result<HashMap<Oid, Vec<String>>> {
+        let mut refs: HashMap<Oid, Vec<String>> = HashMap::new();
+        for (refname, refval) in self.iter_refs(None, true)? {
+            if let Some(value) = refval.value {
+                refs.entry(self.peel(&value)?).or_default().push(refname);
+            }
+        }
+        Ok(refs)
+    }
 }
 
-// Construct a lookup from OIDs to refs which point to it in some way
-pub fn get_oid_to_refs() -> Result<HashMap<Oid, Vec<String>>> {
-    let mut refs: HashMap<Oid, Vec<String>> = HashMap::new();
-    for (refname, refval) in iter_refs(None, true)? {
-        if let Some(value) = refval.value {
-            refs.entry(value).or_default().push(refname);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::tests::temp_repo;
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn check_ref_format_accepts_valid_names() {
+        for name in &["refs/heads/master", "refs/tags/v1.0", "refs/heads/a-b/c_d"] {
+            assert!(check_ref_format(name, false).is_ok(), "{}", name);
+        }
+        assert!(check_ref_format("HEAD", true).is_ok());
+    }
+
+    #[test]
+    fn check_ref_format_rejects_bad_names() {
+        for name in &[
+            "refs/heads/a..b",
+            "refs/heads/.hidden",
+            "refs/heads/a.lock",
+            "refs/heads/trailing.",
+            "refs/heads/trailing/",
+            "refs//heads",
+            "refs/heads/a b",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a?",
+            "refs/heads/a*",
+            "refs/heads/a[b",
+            "refs/heads/a\\b",
+            "refs/heads/a\x7f",
+            "refs/heads/a@{1}",
+            "@",
+            "",
+        ] {
+            assert!(
+                matches!(check_ref_format(name, false), Err(Error::BadRefName(_))),
+                "{:?}",
+                name
+            );
         }
+        // One level names need allowing explicitly
+        assert!(check_ref_format("master", false).is_err());
+        assert!(check_ref_format("master", true).is_ok());
+    }
+
+    fn test_repo() -> (TempDir, Repository, Oid, Oid) {
+        let (dir, repo) = temp_repo();
+        let one = repo.hash_object(b"one", ObjectType::Blob).unwrap();
+        let two = repo.hash_object(b"two", ObjectType::Blob).unwrap();
+        (dir, repo, one, two)
+    }
+
+    fn direct(oid: &Oid) -> RefValue {
+        RefValue {
+            symbolic: false,
+            value: Some(oid.clone()),
+        }
+    }
+
+    fn ref_value(repo: &Repository, ref_: &str) -> Option<Oid> {
+        repo.get_ref(ref_, true).unwrap().value
+    }
+
+    fn lock_files(repo: &Repository) -> Vec<PathBuf> {
+        WalkDir::new(repo.git_dir())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.to_string_lossy().ends_with(LOCK_SUFFIX))
+            .collect()
+    }
+
+    #[test]
+    fn transaction_applies_all_updates() {
+        let (_dir, repo, one, two) = test_repo();
+        let mut transaction = repo.transaction("test");
+        transaction
+            .update("refs/heads/a", direct(&one), true, Expected::Missing)
+            .update("refs/heads/b", direct(&two), true, Expected::Any);
+        transaction.commit().unwrap();
+        assert_eq!(ref_value(&repo, "refs/heads/a"), Some(one));
+        assert_eq!(ref_value(&repo, "refs/heads/b"), Some(two));
+        assert!(lock_files(&repo).is_empty());
+    }
+
+    #[test]
+    fn transaction_with_stale_value_changes_nothing() {
+        let (_dir, repo, one, two) = test_repo();
+        repo.update_ref("refs/heads/a", direct(&one), true, "test")
+            .unwrap();
+        repo.update_ref("refs/heads/b", direct(&one), true, "test")
+            .unwrap();
+
+        let mut transaction = repo.transaction("test");
+        transaction
+            .update(
+                "refs/heads/a",
+                direct(&two),
+                true,
+                Expected::Value(one.clone()),
+            )
+            .delete("refs/heads/b", true, Expected::Any)
+            .update("refs/heads/c", direct(&two), true, Expected::Any)
+            .update(
+                "refs/heads/d",
+                direct(&two),
+                true,
+                Expected::Value(two.clone()),
+            );
+        match transaction.commit() {
+            Err(Error::StaleRef { name, .. }) => assert_eq!(name, "refs/heads/d"),
+            other => panic!("expected a stale ref, got {:?}", other.err()),
+        }
+
+        assert_eq!(ref_value(&repo, "refs/heads/a"), Some(one.clone()));
+        assert_eq!(ref_value(&repo, "refs/heads/b"), Some(one));
+        assert_eq!(ref_value(&repo, "refs/heads/c"), None);
+        assert!(lock_files(&repo).is_empty());
+    }
+
+    #[test]
+    fn transaction_with_bad_name_changes_nothing() {
+        let (_dir, repo, one, _) = test_repo();
+        let mut transaction = repo.transaction("test");
+        transaction
+            .update("refs/heads/a", direct(&one), true, Expected::Any)
+            .update("refs/heads/bad..name", direct(&one), true, Expected::Any);
+        assert!(matches!(transaction.commit(), Err(Error::BadRefName(_))));
+        assert_eq!(ref_value(&repo, "refs/heads/a"), None);
+        assert!(lock_files(&repo).is_empty());
+    }
+
+    #[test]
+    fn transaction_on_locked_ref_changes_nothing() {
+        let (_dir, repo, one, _) = test_repo();
+        let held = RefLock::acquire(repo.ref_path("refs/heads/b")).unwrap();
+        let mut transaction = repo.transaction("test");
+        transaction
+            .update("refs/heads/a", direct(&one), true, Expected::Any)
+            .update("refs/heads/b", direct(&one), true, Expected::Any);
+        assert!(matches!(transaction.commit(), Err(Error::Locked(_))));
+        assert_eq!(ref_value(&repo, "refs/heads/a"), None);
+        // Only the lock held elsewhere remains
+        assert_eq!(lock_files(&repo).len(), 1);
+        drop(held);
+        assert!(lock_files(&repo).is_empty());
     }
-    Ok(refs)
 }
@@ -0,0 +1,106 @@
+use crate::error::{Error, Result};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+fn local_datetime(naive: NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.fixed_offset())
+}
+
+fn parse_relative(date: &str, now: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+    match date {
+        "now" => return Some(now),
+        "yesterday" => return Some(now - Duration::days(1)),
+        _ => {}
+    }
+
+    // Of the form "<count> <unit>[s] ago", with dots also allowed as
+    // separators (e.g. "2.weeks.ago")
+    let date = date.replace('.', " ");
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    if fields.len() != 3 || fields[2] != "ago" {
+        return None;
+    }
+    // Counts too large to represent aren't dates at all
+    let count: i64 = fields[0].parse().ok()?;
+    let duration = match fields[1].trim_end_matches('s') {
+        "second" => Duration::try_seconds(count),
+        "minute" => Duration::try_minutes(count),
+        "hour" => Duration::try_hours(count),
+        "day" => Duration::try_days(count),
+        "week" => Duration::try_weeks(count),
+        "month" => Duration::try_days(count.checked_mul(30)?),
+        "year" => Duration::try_days(count.checked_mul(365)?),
+        _ => return None,
+    };
+    now.checked_sub_signed(duration?)
+}
+
+/// Parse a date given on the command line. Absolute dates may be given as
+/// RFC 2822 or RFC 3339 dates, "YYYY-MM-DD[ HH:MM[:SS]]" in local time or
+/// "@<unix timestamp>"; relative dates as "now", "yesterday" or
+/// "<count> <unit> ago" for units from seconds up to years.
+pub fn parse_date(date: &str) -> Result<DateTime<FixedOffset>> {
+    let date = date.trim();
+    let now: DateTime<FixedOffset> = Local::now().into();
+
+    let parsed = DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_rfc2822(date))
+        .ok()
+        .or_else(|| {
+            let timestamp = date.strip_prefix('@')?.parse().ok()?;
+            DateTime::from_timestamp(timestamp, 0).map(|time| time.fixed_offset())
+        })
+        .or_else(|| {
+            ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+                .or_else(|| {
+                    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+                    day.and_hms_opt(0, 0, 0)
+                })
+                .and_then(local_datetime)
+        })
+        .or_else(|| parse_relative(date, now));
+
+    parsed.ok_or_else(|| Error::InvalidArgument(format!("Invalid date given: {}", date)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_relative_counts_back_from_now() {
+        let now = parse_date("2020-06-15T12:00:00+00:00").unwrap();
+        assert_eq!(
+            parse_relative("3 days ago", now),
+            Some(now - Duration::days(3))
+        );
+        assert_eq!(
+            parse_relative("2.weeks.ago", now),
+            Some(now - Duration::weeks(2))
+        );
+        assert_eq!(
+            parse_relative("1 year ago", now),
+            Some(now - Duration::days(365))
+        );
+    }
+
+    #[test]
+    fn parse_relative_rejects_overflowing_counts() {
+        let now = parse_date("2020-06-15T12:00:00+00:00").unwrap();
+        for date in &[
+            "99999999999 years ago",
+            "9223372036854775807 seconds ago",
+            "99999999999999 months ago",
+        ] {
+            assert_eq!(parse_relative(date, now), None, "{}", date);
+        }
+        assert!(matches!(
+            parse_date("99999999999 years ago"),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,10 +1,11 @@
 use crate::base::Tree;
-use crate::data::{self, ObjectType, Oid};
+use crate::data::{ObjectType, Oid};
+use crate::error::Result;
+use crate::repository::Repository;
 use std::collections::HashMap;
-use std::io::{Result, Write};
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use tempfile;
 
 fn get_none_vector<T>(len: usize) -> Vec<Option<T>> {
     let mut empty = Vec::new();
@@ -35,7 +36,7 @@ pub fn iter_changed_files(
 ) -> Result<impl Iterator<Item = (PathBuf, String)>> {
     let mut output = Vec::new();
     for (path, objects) in compare_trees(&[t_from, t_to])? {
-        let o_from = objects.get(0).unwrap();
+        let o_from = objects.first().unwrap();
         let o_to = objects.get(1).unwrap();
         if o_from != o_to {
             let action = if o_from.is_none() {
@@ -51,47 +52,296 @@ pub fn iter_changed_files(
     Ok(output.into_iter())
 }
 
-pub fn diff_trees(t_from: Tree, t_to: Tree) -> Result<Vec<u8>> {
-    let mut output = Vec::new();
-    for (path, objects) in compare_trees(&[t_from, t_to])? {
-        let o_from = objects.get(0).unwrap();
-        let o_to = objects.get(1).unwrap();
-        if o_from != o_to {
-            output.append(&mut diff_blobs(o_from.as_ref(), o_to.as_ref(), Some(path))?);
-        }
-    }
-    Ok(output)
-}
-
-pub fn diff_blobs(
-    o_from: Option<&Oid>,
-    o_to: Option<&Oid>,
-    path: Option<PathBuf>,
-) -> Result<Vec<u8>> {
-    let mut f_from = tempfile::NamedTempFile::new()?;
-    let mut f_to = tempfile::NamedTempFile::new()?;
+// Produce a unified diff of two files with the external diff program, labelled
+// with the path they are versions of
+fn diff_files(from: &Path, to: &Path, path: Option<PathBuf>) -> Result<Vec<u8>> {
     let path = path
         .map(|buf| buf.to_string_lossy().into_owned())
         .unwrap_or("blob".to_string());
 
-    if o_from.is_some() {
-        f_from.write_all(&data::get_object(o_from.unwrap(), Some(ObjectType::Blob))?.contents)?;
-    }
-    if o_to.is_some() {
-        f_to.write_all(&data::get_object(o_to.unwrap(), Some(ObjectType::Blob))?.contents)?;
-    }
-
     let output = Command::new("diff")
         .arg("--unified")
         .arg("--show-c-function")
         .arg("--label")
         .arg(format!("a/{}", path))
-        .arg(f_from.path())
+        .arg(from)
         .arg("--label")
         .arg(format!("b/{}", path))
-        .arg(f_to.path())
+        .arg(to)
         .stderr(Stdio::null())
         .output()?;
 
     Ok(output.stdout)
 }
+
+impl Repository {
+    pub fn diff_trees(&self, t_from: Tree, t_to: Tree) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for (path, objects) in compare_trees(&[t_from, t_to])? {
+            let o_from = objects.first().unwrap();
+            let o_to = objects.get(1).unwrap();
+            if o_from != o_to {
+                output.append(&mut self.diff_blobs(o_from.as_ref(), o_to.as_ref(), Some(path))?);
+            }
+        }
+        Ok(output)
+    }
+
+    // Write a blob (or nothing, for a missing blob) to a temporary file so that it
+    // can be handed to the external diff program
+    fn blob_tempfile(&self, oid: Option<&Oid>) -> Result<tempfile::NamedTempFile> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        if let Some(oid) = oid {
+            file.write_all(&self.get_object(oid, Some(ObjectType::Blob))?.contents)?;
+        }
+        Ok(file)
+    }
+
+    pub fn diff_blobs(
+        &self,
+        o_from: Option<&Oid>,
+        o_to: Option<&Oid>,
+        path: Option<PathBuf>,
+    ) -> Result<Vec<u8>> {
+        let f_from = self.blob_tempfile(o_from)?;
+        let f_to = self.blob_tempfile(o_to)?;
+        diff_files(f_from.path(), f_to.path(), path)
+    }
+
+    /// Diff a tree against the working tree. Files in the working tree aren't
+    /// stored as objects, so they are diffed in place.
+    pub fn diff_working_tree(&self, t_from: Tree) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for (path, objects) in compare_trees(&[t_from, self.get_working_tree()?])? {
+            let o_from = objects.first().unwrap();
+            let o_to = objects.get(1).unwrap();
+            if o_from != o_to {
+                let f_from = self.blob_tempfile(o_from.as_ref())?;
+                // A deleted file is diffed against an empty one
+                let f_to = match o_to {
+                    Some(_) => None,
+                    None => Some(self.blob_tempfile(None)?),
+                };
+                let to = match &f_to {
+                    Some(f_to) => f_to.path().to_path_buf(),
+                    None => self.work_tree().join(&path),
+                };
+                output.append(&mut diff_files(f_from.path(), &to, Some(path))?);
+            }
+        }
+        Ok(output)
+    }
+
+    /// Merge the changes made to a file in the working tree since the `base`
+    /// blob with the changes in the `theirs` blob, returning the merged
+    /// contents and whether any changes overlapped. Overlapping changes are
+    /// left in the contents between conflict markers.
+    pub fn merge_file(
+        &self,
+        path: &Path,
+        base: &Oid,
+        theirs: &Oid,
+        label: &str,
+    ) -> Result<(Vec<u8>, bool)> {
+        let f_base = self.blob_tempfile(Some(base))?;
+        let f_theirs = self.blob_tempfile(Some(theirs))?;
+
+        let output = Command::new("diff3")
+            .arg("--merge")
+            .arg("--show-overlap")
+            .arg("--text")
+            .arg("--label")
+            .arg("local")
+            .arg("--label")
+            .arg("base")
+            .arg("--label")
+            .arg(label)
+            .arg(path)
+            .arg(f_base.path())
+            .arg(f_theirs.path())
+            .stderr(Stdio::null())
+            .output()?;
+
+        // diff3 exits with 1 when there are conflicts, and 2 on failure
+        match output.status.code() {
+            Some(0) => Ok((output.stdout, false)),
+            Some(1) => Ok((output.stdout, true)),
+            _ => Err(
+                io::Error::other(format!("diff3 could not merge {}", path.to_string_lossy()))
+                    .into(),
+            ),
+        }
+    }
+
+    /// Diff two trees in the form used for patches: files are listed in path
+    /// order and each is introduced by a `diff --git` header noting whether it
+    /// was created or deleted, so the result can be applied again.
+    pub fn diff_trees_as_patch(&self, t_from: Tree, t_to: Tree) -> Result<Vec<u8>> {
+        let mut changes: Vec<(PathBuf, Vec<Option<Oid>>)> = compare_trees(&[t_from, t_to])?
+            .filter(|(_, objects)| objects[0] != objects[1])
+            .collect();
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut output = Vec::new();
+        for (path, objects) in changes {
+            let (o_from, o_to) = (objects[0].as_ref(), objects[1].as_ref());
+            let path_str = path.to_string_lossy();
+            writeln!(output, "diff --git a/{} b/{}", path_str, path_str)?;
+            if o_from.is_none() {
+                writeln!(output, "new file mode 100644")?;
+            } else if o_to.is_none() {
+                writeln!(output, "deleted file mode 100644")?;
+            }
+            output.append(&mut self.diff_blobs(o_from, o_to, Some(path.clone()))?);
+        }
+        Ok(output)
+    }
+}
+
+/// A region of lines which differ between two blobs, taken from a unified
+/// diff hunk header: `from_len` old lines were replaced by the `to_len` lines
+/// starting at `to_start` (numbered from 1, or the line before the change if
+/// no lines were added).
+pub struct LineChange {
+    pub from_len: usize,
+    pub to_start: usize,
+    pub to_len: usize,
+}
+
+/// Parse one side of a hunk header ("start,len", where len defaults to 1).
+pub fn parse_hunk_range(range: &str) -> Option<(usize, usize)> {
+    let mut fields = range.splitn(2, ',');
+    let start = fields.next()?.parse().ok()?;
+    let len = match fields.next() {
+        Some(len) => len.parse().ok()?,
+        None => 1,
+    };
+    Some((start, len))
+}
+
+impl Repository {
+    /// List the regions of lines changed between two blobs, in file order.
+    pub fn diff_blob_lines(
+        &self,
+        o_from: Option<&Oid>,
+        o_to: Option<&Oid>,
+    ) -> Result<Vec<LineChange>> {
+        let f_from = self.blob_tempfile(o_from)?;
+        let f_to = self.blob_tempfile(o_to)?;
+
+        let output = Command::new("diff")
+            .arg("--unified=0")
+            .arg("--text")
+            .arg(f_from.path())
+            .arg(f_to.path())
+            .stderr(Stdio::null())
+            .output()?;
+
+        let mut changes = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            // Hunk headers are of the form "@@ -from_start,len +to_start,len @@"
+            let mut fields = match line.strip_prefix("@@ ") {
+                Some(header) => header.split(' '),
+                None => continue,
+            };
+            let from = fields.next().and_then(|f| f.strip_prefix('-'));
+            let to = fields.next().and_then(|f| f.strip_prefix('+'));
+            if let (Some((_, from_len)), Some((to_start, to_len))) = (
+                from.and_then(parse_hunk_range),
+                to.and_then(parse_hunk_range),
+            ) {
+                changes.push(LineChange {
+                    from_len,
+                    to_start,
+                    to_len,
+                });
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Map each line of the newer blob in a diff to its line number in the older
+/// blob, or None if the line was added or changed.
+pub fn map_lines_to_source(changes: &[LineChange], to_lines: usize) -> Vec<Option<usize>> {
+    let mut mapping = Vec::with_capacity(to_lines);
+    let mut changes = changes.iter().peekable();
+    // Offset of from line numbers relative to to line numbers, accumulated
+    // over the changes preceding the current line
+    let mut delta: isize = 0;
+
+    for line in 1..=to_lines {
+        while let Some(change) = changes.peek() {
+            let last_line = change.to_start + change.to_len.max(1) - 1;
+            if line <= last_line {
+                break;
+            }
+            delta += change.from_len as isize - change.to_len as isize;
+            changes.next();
+        }
+        let changed = changes
+            .peek()
+            .map(|c| c.to_len > 0 && line >= c.to_start)
+            .unwrap_or(false);
+        mapping.push(if changed {
+            None
+        } else {
+            Some((line as isize + delta) as usize)
+        });
+    }
+    mapping
+}
+
+impl Repository {
+    fn count_lines(&self, oid: &Oid) -> Result<HashMap<Vec<u8>, usize>> {
+        let mut counts = HashMap::new();
+        let contents = self.get_object(oid, Some(ObjectType::Blob))?.contents;
+        for line in contents.split(|c| *c == b'\n') {
+            *counts.entry(line.to_vec()).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Find the file in `t_from` which was renamed to `path` in `t_to`, if any.
+    /// Files removed between the trees are candidates: an identical file is
+    /// preferred, otherwise the file sharing the most lines (at least half) with
+    /// the renamed file is chosen.
+    pub fn find_rename_source(
+        &self,
+        t_from: &Tree,
+        t_to: &Tree,
+        path: &Path,
+    ) -> Result<Option<PathBuf>> {
+        let target = match t_to.get(path) {
+            Some(oid) => oid,
+            None => return Ok(None),
+        };
+        let mut candidates: Vec<(&PathBuf, &Oid)> = t_from
+            .iter()
+            .filter(|(candidate, _)| !t_to.contains_key(*candidate))
+            .collect();
+        candidates.sort();
+
+        if let Some((candidate, _)) = candidates.iter().find(|(_, oid)| *oid == target) {
+            return Ok(Some(candidate.to_path_buf()));
+        }
+
+        let target_lines = self.count_lines(target)?;
+        let target_total: usize = target_lines.values().sum();
+        let mut best: Option<(usize, &PathBuf)> = None;
+        for (candidate, oid) in candidates {
+            let candidate_lines = self.count_lines(oid)?;
+            let candidate_total: usize = candidate_lines.values().sum();
+            let common: usize = candidate_lines
+                .iter()
+                .map(|(line, n)| (*n).min(*target_lines.get(line).unwrap_or(&0)))
+                .sum();
+            if common * 2 >= target_total.max(candidate_total)
+                && best.map(|(n, _)| common > n).unwrap_or(true)
+            {
+                best = Some((common, candidate));
+            }
+        }
+        Ok(best.map(|(_, candidate)| candidate.to_path_buf()))
+    }
+}
@@ -0,0 +1,156 @@
+use crate::data::{ObjectType, Oid};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors raised by gitox operations.
+#[derive(Debug)]
+pub enum Error {
+    /// A filesystem or process error
+    Io(io::Error),
+    /// No repository could be found at or above the given path
+    NotARepository(PathBuf),
+    /// No object is stored with the given ID
+    ObjectNotFound(Oid),
+    /// An object (or the data read from it) could not be parsed
+    CorruptObject(String),
+    /// An object exists, but is not of the type it was used as
+    WrongObjectType {
+        oid: Oid,
+        expected: ObjectType,
+        actual: ObjectType,
+    },
+    /// A ref name which isn't allowed, e.g. one escaping the refs directory
+    BadRefName(String),
+    /// A lock file is held, most likely by another gitox process
+    Locked(PathBuf),
+    /// A ref does not have the value an update expected it to have, e.g.
+    /// because another process changed it first
+    StaleRef {
+        name: String,
+        expected: Option<Oid>,
+        actual: Option<Oid>,
+    },
+    /// A name which doesn't resolve to a ref or object
+    UnknownRevision(String),
+    /// An abbreviated object ID matching more than one object
+    AmbiguousRevision { name: String, candidates: Vec<Oid> },
+    /// The working tree has changes which an operation would overwrite
+    DirtyWorktree(Vec<PathBuf>),
+    /// A patch or mailbox which could not be parsed
+    BadPatch(String),
+    /// A patch which doesn't apply to the files it changes
+    PatchDoesNotApply(String),
+    /// A commit or tag whose signature is missing, invalid or untrusted
+    BadSignature(String),
+    /// Any other invalid input from the user, e.g. a malformed date or
+    /// pattern
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Exit status used by the command line for the error, so that scripts
+    /// can tell failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::InvalidArgument(_) => 2,
+            Error::NotARepository(_) => 3,
+            Error::ObjectNotFound(_) => 4,
+            Error::CorruptObject(_) => 5,
+            Error::WrongObjectType { .. } => 6,
+            Error::BadRefName(_) => 7,
+            Error::UnknownRevision(_) => 8,
+            Error::AmbiguousRevision { .. } => 9,
+            Error::DirtyWorktree(_) => 10,
+            Error::BadPatch(_) => 11,
+            Error::PatchDoesNotApply(_) => 12,
+            Error::Locked(_) => 13,
+            Error::StaleRef { .. } => 14,
+            Error::BadSignature(_) => 15,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NotARepository(path) => write!(
+                f,
+                "Not a gitox repository (or any of the parent directories): {}",
+                path.display()
+            ),
+            Error::ObjectNotFound(oid) => write!(f, "Object not found: {}", oid),
+            Error::CorruptObject(reason) => write!(f, "Corrupt object: {}", reason),
+            Error::WrongObjectType {
+                oid,
+                expected,
+                actual,
+            } => write!(f, "Expected {} object, found {}: {}", expected, actual, oid),
+            Error::BadRefName(name) => write!(f, "Invalid ref name: {}", name),
+            Error::Locked(path) => write!(
+                f,
+                "Unable to lock {}: another gitox process seems to be running \
+                 (if not, remove the lock file)",
+                path.display()
+            ),
+            Error::StaleRef {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Ref {} does not have the expected value: expected {}, found {}",
+                name,
+                expected.as_deref().unwrap_or("no value"),
+                actual.as_deref().unwrap_or("no value")
+            ),
+            Error::UnknownRevision(name) => write!(f, "Unknown name given: {}", name),
+            Error::AmbiguousRevision { name, candidates } => write!(
+                f,
+                "Ambiguous name given: {} could be any of {}",
+                name,
+                candidates.join(", ")
+            ),
+            Error::DirtyWorktree(paths) => {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect();
+                write!(
+                    f,
+                    "Working tree has uncommitted changes: {}",
+                    paths.join(", ")
+                )
+            }
+            Error::BadPatch(reason) => write!(f, "Malformed patch: {}", reason),
+            Error::PatchDoesNotApply(reason) => write!(f, "Patch does not apply: {}", reason),
+            Error::BadSignature(reason) => write!(f, "Signature check failed: {}", reason),
+            Error::InvalidArgument(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<walkdir::Error> for Error {
+    fn from(e: walkdir::Error) -> Error {
+        Error::Io(e.into())
+    }
+}
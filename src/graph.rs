@@ -0,0 +1,309 @@
+use crate::data::Oid;
+use crate::error::Result;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Characters used to draw the graph.
+#[derive(Clone, Copy)]
+pub enum Style {
+    Ascii,
+    Unicode,
+}
+
+impl Style {
+    fn draw(self, c: char) -> char {
+        match self {
+            Style::Ascii => c,
+            Style::Unicode => match c {
+                '*' => '●',
+                '|' => '│',
+                '/' => '╱',
+                '\\' => '╲',
+                c => c,
+            },
+        }
+    }
+}
+
+/// Order commits so that every commit comes before its parents, otherwise
+/// keeping to the order given (e.g. that of `iter_commits_and_parents`). Only
+/// parent links between the given commits are considered.
+pub fn topo_sort(oids: Vec<Oid>, parents: &HashMap<Oid, Vec<Oid>>) -> Vec<Oid> {
+    let index: HashMap<&Oid, usize> = oids.iter().enumerate().map(|(i, oid)| (oid, i)).collect();
+    let mut children = vec![0; oids.len()];
+    for oid in &oids {
+        for parent in &parents[oid] {
+            if let Some(i) = index.get(parent) {
+                children[*i] += 1;
+            }
+        }
+    }
+
+    // Repeatedly emit the earliest commit whose children have all been shown
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..oids.len())
+        .filter(|i| children[*i] == 0)
+        .map(Reverse)
+        .collect();
+    let mut sorted = Vec::with_capacity(oids.len());
+    while let Some(Reverse(i)) = ready.pop() {
+        for parent in &parents[&oids[i]] {
+            if let Some(p) = index.get(parent) {
+                children[*p] -= 1;
+                if children[*p] == 0 {
+                    ready.push(Reverse(*p));
+                }
+            }
+        }
+        sorted.push(oids[i].clone());
+    }
+    sorted
+}
+
+/// Incrementally draws the lanes of a commit graph, one commit at a time in
+/// topological order. Each lane is a column waiting for the next commit on a
+/// line of history.
+pub struct Graph {
+    lanes: Vec<Oid>,
+    style: Style,
+}
+
+impl Graph {
+    pub fn new(style: Style) -> Graph {
+        Graph {
+            lanes: Vec::new(),
+            style,
+        }
+    }
+
+    fn render(&self, cells: Vec<(usize, char)>) -> String {
+        let width = cells.iter().map(|(pos, _)| pos + 1).max().unwrap_or(0);
+        let mut line = vec![' '; width];
+        for (pos, c) in cells {
+            line[pos] = self.style.draw(c);
+        }
+        line.into_iter().collect()
+    }
+
+    // Every lane drawn straight down, with an optional marker in one lane
+    fn straight_cells(&self, marked: Option<usize>) -> Vec<(usize, char)> {
+        (0..self.lanes.len())
+            .map(|i| (2 * i, if Some(i) == marked { '*' } else { '|' }))
+            .collect()
+    }
+
+    // Line drawn alongside further lines of text for the last commit
+    fn padding(&self) -> String {
+        self.render(self.straight_cells(None))
+    }
+
+    // Add the next commit to the graph, returning the lane it was placed in
+    // and the line containing the commit followed by any lines needed to
+    // branch out to its parents and to merge lanes which now lead to the
+    // same commit
+    fn next(&mut self, oid: &Oid, parents: &[Oid]) -> (usize, Vec<String>) {
+        let col = match self.lanes.iter().position(|lane| lane == oid) {
+            Some(col) => col,
+            None => {
+                self.lanes.push(oid.clone());
+                self.lanes.len() - 1
+            }
+        };
+        let mut lines = vec![self.render(self.straight_cells(Some(col)))];
+
+        match parents.split_first() {
+            None => {
+                // The line of history ends here, so later lanes move left
+                self.lanes.remove(col);
+                if col < self.lanes.len() {
+                    let mut cells: Vec<(usize, char)> = (0..col).map(|i| (2 * i, '|')).collect();
+                    cells.extend((col..self.lanes.len()).map(|i| (2 * i + 1, '/')));
+                    lines.push(self.render(cells));
+                }
+            }
+            Some((first, rest)) => {
+                self.lanes[col] = first.clone();
+                // A merge branches out with a new lane for each other parent
+                for (n, parent) in rest.iter().enumerate() {
+                    let new_lane = col + n + 1;
+                    let mut cells: Vec<(usize, char)> =
+                        (0..new_lane).map(|i| (2 * i, '|')).collect();
+                    cells.extend((new_lane..=self.lanes.len()).map(|i| (2 * i - 1, '\\')));
+                    self.lanes.insert(new_lane, parent.clone());
+                    lines.push(self.render(cells));
+                }
+            }
+        }
+
+        // Lanes waiting for the same commit are joined into the leftmost
+        while let Some((target, lane)) = self.find_duplicate_lane() {
+            let mut cells: Vec<(usize, char)> = (0..lane).map(|i| (2 * i, '|')).collect();
+            cells.extend(
+                (2 * target + 1..2 * lane - 1)
+                    .step_by(2)
+                    .map(|pos| (pos, '_')),
+            );
+            cells.extend((lane..self.lanes.len()).map(|i| (2 * i - 1, '/')));
+            self.lanes.remove(lane);
+            lines.push(self.render(cells));
+        }
+        (col, lines)
+    }
+
+    /// Add the next commit to the graph and draw it to the left of the
+    /// commit's text, continuing the lanes alongside any further text lines.
+    pub fn draw_commit(&mut self, oid: &Oid, parents: &[Oid], text: &str) -> String {
+        let (_, graph_lines) = self.next(oid, parents);
+        let text_lines: Vec<&str> = text.split('\n').collect();
+        let padding = self.padding();
+        let width = graph_lines
+            .iter()
+            .chain(std::iter::once(&padding))
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        let mut output = Vec::new();
+        for i in 0..graph_lines.len().max(text_lines.len()) {
+            let graph_line = graph_lines.get(i).unwrap_or(&padding);
+            let text_line = text_lines.get(i).cloned().unwrap_or("");
+            let pad = width - graph_line.chars().count();
+            let line = format!("{}{} {}", graph_line, " ".repeat(pad), text_line);
+            output.push(line.trim_end().to_string());
+        }
+        output.join("\n")
+    }
+
+    fn find_duplicate_lane(&self) -> Option<(usize, usize)> {
+        let mut seen: HashMap<&Oid, usize> = HashMap::new();
+        for (i, lane) in self.lanes.iter().enumerate() {
+            if let Some(target) = seen.get(lane) {
+                return Some((*target, i));
+            }
+            seen.insert(lane, i);
+        }
+        None
+    }
+}
+
+/// Assign each commit (given in topological order) the column it would be
+/// drawn in by `Graph`.
+pub fn columns(oids: &[Oid], parents: &HashMap<Oid, Vec<Oid>>) -> Vec<usize> {
+    let mut graph = Graph::new(Style::Ascii);
+    oids.iter()
+        .map(|oid| graph.next(oid, &parents[oid]).0)
+        .collect()
+}
+
+// The nearest ancestors of a commit (or the commit itself) among those shown,
+// remembering them for each hidden commit passed through on the way
+fn shown_ancestors<F>(
+    start: &Oid,
+    shown: &HashSet<&Oid>,
+    nearest: &mut HashMap<Oid, Vec<Oid>>,
+    load_parents: &mut F,
+) -> Result<Vec<Oid>>
+where
+    F: FnMut(&Oid) -> Result<Vec<Oid>>,
+{
+    if shown.contains(start) {
+        return Ok(vec![start.clone()]);
+    }
+    // Each hidden commit is visited once to load its parents, then again
+    // once its hidden parents have been resolved
+    let mut loaded: HashMap<Oid, Vec<Oid>> = HashMap::new();
+    let mut stack = vec![start.clone()];
+    while let Some(oid) = stack.pop() {
+        if nearest.contains_key(&oid) {
+            continue;
+        }
+        match loaded.remove(&oid) {
+            None => {
+                let parents = load_parents(&oid)?;
+                stack.push(oid.clone());
+                stack.extend(
+                    parents
+                        .iter()
+                        .filter(|parent| !shown.contains(parent) && !nearest.contains_key(*parent))
+                        .cloned(),
+                );
+                loaded.insert(oid, parents);
+            }
+            Some(parents) => {
+                let mut ancestors: Vec<Oid> = Vec::new();
+                for parent in parents {
+                    let found = match shown.contains(&parent) {
+                        true => vec![parent],
+                        false => nearest.get(&parent).cloned().unwrap_or_default(),
+                    };
+                    for ancestor in found {
+                        if !ancestors.contains(&ancestor) {
+                            ancestors.push(ancestor);
+                        }
+                    }
+                }
+                nearest.insert(oid, ancestors);
+            }
+        }
+    }
+    Ok(nearest.get(start).cloned().unwrap_or_default())
+}
+
+/// Rewrite each commit's parents to those among the commits being drawn, as
+/// git's parent rewriting does: a hidden parent is replaced by its nearest
+/// shown ancestors, found through `load_parents`, so lines of history still
+/// join up when commits in between are left out. Duplicates are removed.
+pub fn visible_parents<F>(
+    oids: &[Oid],
+    mut parents: HashMap<Oid, Vec<Oid>>,
+    mut load_parents: F,
+) -> Result<HashMap<Oid, Vec<Oid>>>
+where
+    F: FnMut(&Oid) -> Result<Vec<Oid>>,
+{
+    let shown: HashSet<&Oid> = oids.iter().collect();
+    let mut nearest = HashMap::new();
+    for commit_parents in parents.values_mut() {
+        let mut rewritten: Vec<Oid> = Vec::new();
+        for parent in commit_parents.iter() {
+            for ancestor in shown_ancestors(parent, &shown, &mut nearest, &mut load_parents)? {
+                if !rewritten.contains(&ancestor) {
+                    rewritten.push(ancestor);
+                }
+            }
+        }
+        *commit_parents = rewritten;
+    }
+    Ok(parents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visible_parents_rewrites_hidden_parents_to_shown_ancestors() {
+        // a - b - d (merge of b and c) and a - c, with only a and d shown
+        let history: HashMap<Oid, Vec<Oid>> = [
+            ("d", vec!["b", "c"]),
+            ("c", vec!["a"]),
+            ("b", vec!["a"]),
+            ("a", vec![]),
+        ]
+        .iter()
+        .map(|(oid, parents)| {
+            (
+                oid.to_string(),
+                parents.iter().map(|p| p.to_string()).collect(),
+            )
+        })
+        .collect();
+        let shown = vec!["d".to_string(), "a".to_string()];
+        let parents = shown
+            .iter()
+            .map(|oid| (oid.clone(), history[oid].clone()))
+            .collect();
+        let parents = visible_parents(&shown, parents, |oid| Ok(history[oid].clone())).unwrap();
+        assert_eq!(parents["d"], vec!["a".to_string()]);
+        assert!(parents["a"].is_empty());
+    }
+}
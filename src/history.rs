@@ -0,0 +1,275 @@
+use crate::base::{Commit, Tree};
+use crate::data::{ObjectType, Oid};
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use chrono::{DateTime, FixedOffset};
+use regex::Regex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// Search for commits by the content of their changes.
+pub enum Pickaxe {
+    /// Commits changing the number of occurrences of a string (`-S`)
+    Occurrences(String),
+    /// Commits adding or removing lines matching a regex (`-G`)
+    Lines(Regex),
+}
+
+/// Criteria for choosing which commits `log` shows. A commit must match all
+/// of the criteria given.
+pub struct Filter {
+    /// Only show commits changing files at or beneath these paths
+    pub paths: Vec<PathBuf>,
+    /// Follow the history of the single path in `paths` across renames
+    pub follow: bool,
+    pub author: Option<Regex>,
+    pub grep: Option<Regex>,
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+    pub pickaxe: Option<Pickaxe>,
+}
+
+/// Compile a pattern given on the command line.
+pub fn parse_regex(pattern: &str) -> Result<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| Error::InvalidArgument(format!("Invalid pattern '{}': {}", pattern, e)))
+}
+
+impl Filter {
+    fn matches_metadata(&self, commit: &Commit) -> bool {
+        if let Some(author) = &self.author {
+            let matched = commit
+                .author
+                .as_ref()
+                .map(|a| author.is_match(&format!("{} <{}>", a.name, a.email)))
+                .unwrap_or(false);
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(grep) = &self.grep {
+            if !grep.is_match(&commit.message) {
+                return false;
+            }
+        }
+        if self.since.is_some() || self.until.is_some() {
+            // Commits are dated by when they were committed, as in Git
+            let time = match commit.committer.as_ref().or(commit.author.as_ref()) {
+                Some(signature) => signature.time,
+                None => return false,
+            };
+            if self.since.map(|since| time < since).unwrap_or(false)
+                || self.until.map(|until| time > until).unwrap_or(false)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_limited_by_changes(&self) -> bool {
+        !self.paths.is_empty() || self.pickaxe.is_some()
+    }
+}
+
+fn is_selected(path: &Path, paths: &[PathBuf]) -> bool {
+    paths.is_empty() || paths.iter().any(|p| path.starts_with(p))
+}
+
+fn get_blob(repo: &Repository, oid: Option<&Oid>) -> Result<Vec<u8>> {
+    match oid {
+        Some(oid) => Ok(repo.get_object(oid, Some(ObjectType::Blob))?.contents),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn count_occurrences(haystack: &[u8], needle: &[u8]) -> usize {
+    if needle.is_empty() {
+        return 0;
+    }
+    haystack
+        .windows(needle.len())
+        .filter(|w| *w == needle)
+        .count()
+}
+
+fn pickaxe_matches(
+    repo: &Repository,
+    pickaxe: &Pickaxe,
+    path: &Path,
+    o_from: Option<&Oid>,
+    o_to: Option<&Oid>,
+) -> Result<bool> {
+    match pickaxe {
+        Pickaxe::Occurrences(needle) => {
+            Ok(
+                count_occurrences(&get_blob(repo, o_from)?, needle.as_bytes())
+                    != count_occurrences(&get_blob(repo, o_to)?, needle.as_bytes()),
+            )
+        }
+        Pickaxe::Lines(regex) => {
+            let diff = repo.diff_blobs(o_from, o_to, Some(path.to_path_buf()))?;
+            Ok(diff
+                .split(|c| *c == b'\n')
+                .filter(|line| !line.starts_with(b"+++") && !line.starts_with(b"---"))
+                .filter(|line| line.starts_with(b"+") || line.starts_with(b"-"))
+                .any(|line| regex.is_match(&String::from_utf8_lossy(&line[1..]))))
+        }
+    }
+}
+
+// The paths which differ between two trees, with their objects in each
+fn changed_paths<'a>(
+    parent: &'a Tree,
+    tree: &'a Tree,
+) -> impl Iterator<Item = (&'a PathBuf, Option<&'a Oid>, Option<&'a Oid>)> {
+    let removed = parent
+        .iter()
+        .filter(move |(path, _)| !tree.contains_key(*path))
+        .map(|(path, oid)| (path, Some(oid), None));
+    let added_or_modified = tree
+        .iter()
+        .filter(move |(path, oid)| parent.get(*path) != Some(oid))
+        .map(move |(path, oid)| (path, parent.get(path), Some(oid)));
+    removed.chain(added_or_modified)
+}
+
+// Decide whether a commit changes any of the selected paths (and satisfies
+// the pickaxe, if given) compared to its parent's tree
+fn changes_match(
+    repo: &Repository,
+    filter: &Filter,
+    paths: &[PathBuf],
+    parent: &Tree,
+    tree: &Tree,
+) -> Result<bool> {
+    for (path, o_from, o_to) in changed_paths(parent, tree) {
+        if !is_selected(path, paths) {
+            continue;
+        }
+        match &filter.pickaxe {
+            Some(pickaxe) => {
+                if pickaxe_matches(repo, pickaxe, path, o_from, o_to)? {
+                    return Ok(true);
+                }
+            }
+            None => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+/// Iterate over the commits reachable from `oids` which match the filter, in
+/// the same order as `iter_commits_and_parents`. When limiting by path,
+/// history is simplified to the commits which changed the paths: at a merge
+/// which took the paths unchanged from one of its parents ("TREESAME" to
+/// it), only that parent's history is followed, and the merge isn't shown.
+/// Given a limit, the walk stops once that many commits have matched.
+pub fn iter_filtered_commits(
+    repo: &Repository,
+    oids: &[Oid],
+    filter: &Filter,
+    limit: Option<usize>,
+) -> Result<impl Iterator<Item = Oid>> {
+    if filter.follow && filter.paths.len() != 1 {
+        return Err(Error::InvalidArgument(
+            "--follow requires exactly one path".to_string(),
+        ));
+    }
+
+    let mut commits = Vec::new();
+    let mut trees: HashMap<Oid, Tree> = HashMap::new();
+    // Paths being followed in each commit still to be visited, which differ
+    // from the given paths once a rename has been followed
+    let mut followed: HashMap<Oid, Vec<PathBuf>> = HashMap::new();
+
+    // Commits are walked as in iter_commits_and_parents, except that only the
+    // parents kept by simplification are queued
+    let mut queue = oids
+        .iter()
+        .map(|oid| repo.peel(oid))
+        .collect::<Result<VecDeque<Oid>>>()?;
+    let mut visited: HashSet<Oid> = HashSet::new();
+    while let Some(oid) = queue.pop_front() {
+        if limit.is_some_and(|limit| commits.len() >= limit) {
+            break;
+        }
+        if !visited.insert(oid.clone()) {
+            continue;
+        }
+        let commit = repo.get_commit(&oid)?;
+        let paths = followed
+            .remove(&oid)
+            .unwrap_or_else(|| filter.paths.clone());
+
+        let mut matched = filter.matches_metadata(&commit);
+        let mut parents = commit.parents;
+        if filter.is_limited_by_changes() {
+            let tree = match trees.remove(&oid) {
+                Some(tree) => tree,
+                None => repo.get_tree(Some(&commit.tree), PathBuf::new())?,
+            };
+            let mut parent_trees = Vec::with_capacity(parents.len());
+            for parent in &parents {
+                parent_trees.push(match trees.remove(parent) {
+                    Some(parent_tree) => parent_tree,
+                    None => {
+                        let parent_commit = repo.get_commit(parent)?;
+                        repo.get_tree(Some(&parent_commit.tree), PathBuf::new())?
+                    }
+                });
+            }
+
+            let treesame = match filter.paths.is_empty() {
+                true => None,
+                false => parent_trees.iter().position(|parent_tree| {
+                    !changed_paths(parent_tree, &tree).any(|(path, _, _)| is_selected(path, &paths))
+                }),
+            };
+            let empty = Tree::new();
+            matched = matched
+                && treesame.is_none()
+                // Otherwise changes are judged against the first parent
+                && changes_match(repo, filter, &paths, parent_trees.first().unwrap_or(&empty), &tree)?;
+
+            if let Some(i) = treesame {
+                parents = vec![parents.swap_remove(i)];
+                parent_trees = vec![parent_trees.swap_remove(i)];
+            }
+            for (parent, parent_tree) in parents.iter().zip(parent_trees) {
+                let mut parent_paths = paths.clone();
+                if filter.follow {
+                    for path in parent_paths.iter_mut() {
+                        if !parent_tree.contains_key(path) {
+                            if let Some(source) =
+                                repo.find_rename_source(&parent_tree, &tree, path)?
+                            {
+                                *path = source;
+                            }
+                        }
+                    }
+                }
+                // A commit reached from several children follows the paths of
+                // all of them
+                let entry = followed.entry(parent.clone()).or_default();
+                for path in parent_paths {
+                    if !entry.contains(&path) {
+                        entry.push(path);
+                    }
+                }
+                trees.entry(parent.clone()).or_insert(parent_tree);
+            }
+        }
+
+        let mut parents = parents.into_iter();
+        if let Some(parent) = parents.next() {
+            queue.push_front(parent);
+        }
+        queue.extend(parents);
+
+        if matched {
+            commits.push(oid);
+        }
+    }
+    Ok(commits.into_iter())
+}
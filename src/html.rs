@@ -0,0 +1,468 @@
+use crate::base::{Commit, Tree};
+use crate::data::{is_oid, ObjectType, Oid};
+use crate::error::{Error, Result};
+use crate::graph;
+use crate::pretty::DATE_FORMAT;
+use crate::repository::Repository;
+use crate::visualize::{self, escape_xml};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Number of commits listed on each page of the log.
+pub const LOG_PAGE_SIZE: usize = 50;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+nav a { margin-right: 1em; }
+table { border-collapse: collapse; width: 100%; }
+td, th { text-align: left; padding: 0.2em 0.6em; vertical-align: top; }
+tr:nth-child(even) { background: #f4f4f4; }
+pre { background: #f8f8f8; padding: 0.6em; overflow-x: auto; }
+code, pre, .oid { font-family: monospace; }
+.ref { background: #dfd; border: 1px solid #9c9; border-radius: 3px; padding: 0 0.3em; font-size: small; }
+.file { font-weight: bold; color: #000; background: #e8e8ff; display: block; }
+.hunk { color: #707; }
+.add { color: #060; background: #efe; display: block; }
+.del { color: #900; background: #fee; display: block; }
+.lineno { color: #999; user-select: none; padding-right: 1em; text-align: right; }
+";
+
+/// A read-only view of the repository rendered as HTML pages. Pages are
+/// addressed by paths relative to the root of the site (e.g.
+/// "commit/<oid>.html") and only link to each other by relative paths, so
+/// the site can be written out as files or served as it is.
+pub struct Site<'a> {
+    repo: &'a Repository,
+    /// Commits reachable from any ref, newest first
+    commits: Vec<Oid>,
+    refs: Vec<(String, Oid)>,
+    decorations: HashMap<Oid, Vec<String>>,
+    /// Trees and blobs reachable from the commits, as "tree" or "blob" and
+    /// the object ID
+    objects: Vec<(&'static str, Oid)>,
+    reachable: HashSet<Oid>,
+}
+
+fn page(title: &str, depth: usize, body: &str) -> String {
+    let root = "../".repeat(depth);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>{style}</style>\n</head>\n<body>\n<nav><a href=\"{root}index.html\">refs</a>\
+         <a href=\"{root}log/1.html\">log</a><a href=\"{root}graph.html\">graph</a></nav>\n\
+         <h1>{title}</h1>\n{body}\n</body>\n</html>\n",
+        title = escape_xml(title),
+        style = STYLE,
+        root = root,
+        body = body,
+    )
+}
+
+fn short(oid: &str) -> &str {
+    oid.get(..10).unwrap_or(oid)
+}
+
+// Link to the page of an object, given the object ID read from a commit or
+// tree and the (escaped) text to show. Object IDs which aren't well formed
+// can't have a page, so are never put into the link.
+fn object_link(kind: &str, oid: &str, root: &str, class: &str, text: &str) -> String {
+    match is_oid(oid) {
+        true => format!(
+            "<a{} href=\"{}{}/{}.html\">{}</a>",
+            class, root, kind, oid, text
+        ),
+        false => format!("<span{}>{}</span>", class, text),
+    }
+}
+
+fn commit_link(oid: &Oid, root: &str) -> String {
+    object_link(
+        "commit",
+        oid,
+        root,
+        " class=\"oid\"",
+        &escape_xml(short(oid)),
+    )
+}
+
+fn signature_text(commit: &Commit) -> (String, String) {
+    match &commit.author {
+        Some(author) => (
+            author.name.clone(),
+            author.time.format(DATE_FORMAT).to_string(),
+        ),
+        None => ("unknown".to_string(), "".to_string()),
+    }
+}
+
+/// Whether a blob's contents should be treated as binary rather than text.
+pub fn is_binary(contents: &[u8]) -> bool {
+    contents.contains(&0) || std::str::from_utf8(contents).is_err()
+}
+
+/// The changes a commit made against its first parent, as a patch.
+pub fn commit_diff(repo: &Repository, commit: &Commit) -> Result<Vec<u8>> {
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => repo.get_tree(Some(&repo.get_commit(parent)?.tree), PathBuf::new())?,
+        None => Tree::new(),
+    };
+    let tree = repo.get_tree(Some(&commit.tree), PathBuf::new())?;
+    repo.diff_trees_as_patch(parent_tree, tree)
+}
+
+fn render_diff(diff: &[u8]) -> String {
+    let mut out = String::from("<pre>");
+    for line in String::from_utf8_lossy(diff).lines() {
+        let class = if line.starts_with("diff --git") {
+            Some("file")
+        } else if line.starts_with("+++") || line.starts_with("---") {
+            None
+        } else if line.starts_with("@@") {
+            Some("hunk")
+        } else if line.starts_with('+') {
+            Some("add")
+        } else if line.starts_with('-') {
+            Some("del")
+        } else {
+            None
+        };
+        match class {
+            Some(class) => out.push_str(&format!(
+                "<span class=\"{}\">{}</span>",
+                class,
+                escape_xml(line)
+            )),
+            None => {
+                out.push_str(&escape_xml(line));
+                out.push('\n');
+            }
+        }
+    }
+    out.push_str("</pre>");
+    out
+}
+
+// Collect the trees and blobs reachable from a tree, skipping those already
+// seen and malformed object IDs, which have no page
+fn tree_objects(
+    repo: &Repository,
+    oid: &Oid,
+    seen: &mut HashSet<Oid>,
+    objects: &mut Vec<(&'static str, Oid)>,
+) -> Result<()> {
+    if !is_oid(oid) || !seen.insert(oid.clone()) {
+        return Ok(());
+    }
+    objects.push(("tree", oid.clone()));
+    for entry in repo.get_tree_entries(Some(oid))? {
+        match entry.t {
+            ObjectType::Tree => tree_objects(repo, &entry.oid, seen, objects)?,
+            _ => {
+                if is_oid(&entry.oid) && seen.insert(entry.oid.clone()) {
+                    objects.push(("blob", entry.oid));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Site<'a> {
+    /// Collect the refs of the repository and the commits reachable from
+    /// them.
+    pub fn load(repo: &'a Repository) -> Result<Site<'a>> {
+        let mut refs = Vec::new();
+        for (refname, refvalue) in repo.iter_refs(None, true)? {
+            if let Some(value) = refvalue.value {
+                refs.push((refname, repo.peel(&value)?));
+            }
+        }
+        let mut tips: Vec<Oid> = Vec::new();
+        for (_, oid) in &refs {
+            if !tips.contains(oid) {
+                tips.push(oid.clone());
+            }
+        }
+
+        let oids: Vec<Oid> = repo.iter_commits_and_parents(tips.into_iter())?.collect();
+        let mut parents = HashMap::new();
+        let mut reachable = HashSet::new();
+        let mut objects = Vec::new();
+        for oid in &oids {
+            let commit = repo.get_commit(oid)?;
+            tree_objects(repo, &commit.tree, &mut reachable, &mut objects)?;
+            parents.insert(oid.clone(), commit.parents);
+        }
+        Ok(Site {
+            repo,
+            commits: graph::topo_sort(oids, &parents),
+            refs,
+            decorations: repo.get_oid_to_refs()?,
+            objects,
+            reachable,
+        })
+    }
+
+    /// The repository the site shows.
+    pub fn repo(&self) -> &'a Repository {
+        self.repo
+    }
+
+    /// Commits reachable from any ref, newest first.
+    pub fn commits(&self) -> &[Oid] {
+        &self.commits
+    }
+
+    /// Refs of the repository (with symbolic refs resolved) and the commits
+    /// they point to.
+    pub fn refs(&self) -> &[(String, Oid)] {
+        &self.refs
+    }
+
+    /// Whether a tree or blob is reachable from a ref, and so has a page.
+    pub fn has_object(&self, oid: &str) -> bool {
+        self.reachable.contains(oid)
+    }
+
+    /// Number of pages the log is split across.
+    pub fn log_pages(&self) -> usize {
+        1.max(self.commits.len().div_ceil(LOG_PAGE_SIZE))
+    }
+
+    /// Render the page at a path within the site, or `None` if there is no
+    /// such page.
+    pub fn render(&self, path: &str) -> Result<Option<String>> {
+        let path = path.trim_start_matches('/');
+        if path.is_empty() || path == "index.html" {
+            return self.index().map(Some);
+        }
+        if path == "graph.html" {
+            return self.graph_page().map(Some);
+        }
+        let (dir, name) = match path.split_once('/') {
+            Some((dir, file)) => match file.strip_suffix(".html") {
+                Some(name) => (dir, name),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        match dir {
+            "log" => match name.parse() {
+                Ok(n) if n >= 1 && n <= self.log_pages() => self.log_page(n).map(Some),
+                _ => Ok(None),
+            },
+            "commit" if self.commits.iter().any(|oid| oid == name) => {
+                self.commit_page(&name.to_string()).map(Some)
+            }
+            "tree" | "blob" if self.has_object(name) => {
+                let oid = name.to_string();
+                let result = match dir {
+                    "tree" => self.tree_page(&oid),
+                    _ => self.blob_page(&oid),
+                };
+                match result {
+                    Ok(page) => Ok(Some(page)),
+                    Err(Error::ObjectNotFound(_)) | Err(Error::WrongObjectType { .. }) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The list of refs and the commits they point to.
+    pub fn index(&self) -> Result<String> {
+        let mut body =
+            String::from("<table>\n<tr><th>Ref</th><th>Commit</th><th>Subject</th></tr>\n");
+        for (name, oid) in &self.refs {
+            let commit = self.repo.get_commit(oid)?;
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape_xml(name),
+                commit_link(oid, ""),
+                escape_xml(commit.subject())
+            ));
+        }
+        body.push_str("</table>");
+        Ok(page("Refs", 0, &body))
+    }
+
+    /// One page of the log, numbered from 1.
+    pub fn log_page(&self, n: usize) -> Result<String> {
+        let mut body = String::from(
+            "<table>\n<tr><th>Commit</th><th>Subject</th><th>Author</th><th>Date</th></tr>\n",
+        );
+        for oid in self
+            .commits
+            .iter()
+            .skip((n - 1) * LOG_PAGE_SIZE)
+            .take(LOG_PAGE_SIZE)
+        {
+            let commit = self.repo.get_commit(oid)?;
+            let (author, date) = signature_text(&commit);
+            let refs: String = self
+                .decorations
+                .get(oid)
+                .map(|refs| {
+                    refs.iter()
+                        .map(|r| format!(" <span class=\"ref\">{}</span>", escape_xml(r)))
+                        .collect()
+                })
+                .unwrap_or_default();
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}{}</td><td>{}</td><td>{}</td></tr>\n",
+                commit_link(oid, "../"),
+                escape_xml(commit.subject()),
+                refs,
+                escape_xml(&author),
+                escape_xml(&date)
+            ));
+        }
+        body.push_str("</table>\n<p>");
+        if n > 1 {
+            body.push_str(&format!("<a href=\"{}.html\">newer</a> ", n - 1));
+        }
+        body.push_str(&format!("page {} of {}", n, self.log_pages()));
+        if n < self.log_pages() {
+            body.push_str(&format!(" <a href=\"{}.html\">older</a>", n + 1));
+        }
+        body.push_str("</p>");
+        Ok(page(&format!("Log (page {})", n), 1, &body))
+    }
+
+    /// A commit's details and its changes against its first parent.
+    pub fn commit_page(&self, oid: &Oid) -> Result<String> {
+        let commit = self.repo.get_commit(oid)?;
+        let mut body = String::from("<table>\n");
+        body.push_str(&format!(
+            "<tr><th>Commit</th><td class=\"oid\">{}</td></tr>\n",
+            oid
+        ));
+        for (role, signature) in [("Author", &commit.author), ("Committer", &commit.committer)] {
+            if let Some(signature) = signature {
+                body.push_str(&format!(
+                    "<tr><th>{}</th><td>{} &lt;{}&gt; {}</td></tr>\n",
+                    role,
+                    escape_xml(&signature.name),
+                    escape_xml(&signature.email),
+                    signature.time.format(DATE_FORMAT)
+                ));
+            }
+        }
+        for parent in &commit.parents {
+            body.push_str(&format!(
+                "<tr><th>Parent</th><td>{}</td></tr>\n",
+                commit_link(parent, "../")
+            ));
+        }
+        body.push_str(&format!(
+            "<tr><th>Tree</th><td>{}</td></tr>\n</table>\n",
+            object_link(
+                "tree",
+                &commit.tree,
+                "../",
+                " class=\"oid\"",
+                &escape_xml(short(&commit.tree))
+            )
+        ));
+        body.push_str(&format!("<pre>{}</pre>\n", escape_xml(&commit.message)));
+
+        body.push_str(&render_diff(&commit_diff(self.repo, &commit)?));
+        Ok(page(
+            &format!("{} {}", short(oid), commit.subject()),
+            1,
+            &body,
+        ))
+    }
+
+    /// The entries of a tree, linking to subtrees and blobs.
+    pub fn tree_page(&self, oid: &Oid) -> Result<String> {
+        let mut entries = self.repo.get_tree_entries(Some(oid))?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut body = String::from("<table>\n");
+        for entry in entries {
+            let (kind, name) = match entry.t {
+                ObjectType::Tree => ("tree", format!("{}/", entry.name)),
+                _ => ("blob", entry.name.clone()),
+            };
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                kind,
+                object_link(kind, &entry.oid, "../", "", &escape_xml(&name))
+            ));
+        }
+        body.push_str("</table>");
+        Ok(page(&format!("Tree {}", short(oid)), 1, &body))
+    }
+
+    /// The contents of a blob, with line numbers.
+    pub fn blob_page(&self, oid: &Oid) -> Result<String> {
+        let contents = self.repo.get_object(oid, Some(ObjectType::Blob))?.contents;
+        let body = if is_binary(&contents) {
+            format!("<p>Binary file ({} bytes)</p>", contents.len())
+        } else {
+            let mut body = String::from("<table>\n");
+            for (i, line) in String::from_utf8_lossy(&contents).lines().enumerate() {
+                body.push_str(&format!(
+                    "<tr><td class=\"lineno\">{}</td><td><code>{}</code></td></tr>\n",
+                    i + 1,
+                    escape_xml(line)
+                ));
+            }
+            body.push_str("</table>");
+            body
+        };
+        Ok(page(&format!("Blob {}", short(oid)), 1, &body))
+    }
+
+    /// The commit graph of the repository, drawn as an SVG image.
+    pub fn graph_page(&self) -> Result<String> {
+        let graph = visualize::build(
+            self.repo,
+            &visualize::Options {
+                depth: None,
+                refs: None,
+                labels: visualize::Labels {
+                    subject: true,
+                    author: false,
+                },
+            },
+        )?;
+        Ok(page("Graph", 0, &graph.to_svg()))
+    }
+
+    /// Paths of every page in the site.
+    pub fn paths(&self) -> Result<Vec<String>> {
+        let mut paths = vec!["index.html".to_string(), "graph.html".to_string()];
+        paths.extend((1..=self.log_pages()).map(|n| format!("log/{}.html", n)));
+        paths.extend(
+            self.commits
+                .iter()
+                .map(|oid| format!("commit/{}.html", oid)),
+        );
+        paths.extend(
+            self.objects
+                .iter()
+                .map(|(kind, oid)| format!("{}/{}.html", kind, oid)),
+        );
+        Ok(paths)
+    }
+}
+
+/// Write every page of the site into a directory, returning the number of
+/// pages written.
+pub fn export(repo: &Repository, dir: &Path) -> Result<usize> {
+    let site = Site::load(repo)?;
+    let paths = site.paths()?;
+    for path in &paths {
+        let contents = site
+            .render(path)?
+            .ok_or_else(|| Error::CorruptObject(format!("Could not render page: {}", path)))?;
+        let file = dir.join(path);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(file, contents)?;
+    }
+    Ok(paths.len())
+}
@@ -0,0 +1,31 @@
+//! Git clone written in Rust for education.
+//!
+//! A repository is accessed through a [`Repository`] handle, opened at an
+//! explicit path, whose methods cover objects, refs, trees, commits and
+//! diffs. The remaining modules build higher level features (history
+//! filtering, blame, patches, reflogs, signing, visualization) on top of it.
+
+pub mod base;
+pub mod blame;
+pub mod cache;
+pub mod data;
+pub mod date;
+pub mod diff;
+pub mod error;
+pub mod graph;
+pub mod history;
+pub mod html;
+pub mod parallel;
+pub mod patch;
+pub mod pretty;
+pub mod reflog;
+pub mod repository;
+pub mod serve;
+pub mod sign;
+pub mod tui;
+pub mod visualize;
+
+pub use base::{Commit, Signature, Tree};
+pub use data::{ObjectType, Oid, RefValue};
+pub use error::{Error, Result};
+pub use repository::Repository;
@@ -2,16 +2,15 @@
 extern crate clap;
 use clap::{Arg, SubCommand};
 
-mod base;
 mod cli;
-mod data;
-mod diff;
 
-fn main() -> std::io::Result<()> {
+fn main() {
     let matches = clap_app!(gitox =>
         (version: "0.1.0")
         (author: "Callum Ward <wards.callum@gmail.com")
         (about: "Git clone written in Rust for education")
+        (@arg git_dir: --("git-dir") +takes_value "Path to the repository's .gitox directory (by default found by searching up from the current directory)")
+        (@arg work_tree: --("work-tree") +takes_value "Path to the top of the working tree (by default the directory containing the .gitox directory)")
         (@subcommand init =>
             (about: "Initialize the repository")
         )
@@ -19,15 +18,46 @@ fn main() -> std::io::Result<()> {
             (about: "Get repository status")
         )
         (@subcommand k =>
-            (about: "Visualize the repository")
+            (about: "Visualize the repository, or export its commit graph")
+            (@arg format: -f --format +takes_value possible_value[dot svg json mermaid] "Format to export the graph in")
+            (@arg output: -o --output +takes_value "File to export the graph to ('-' for stdout)")
+            (@arg depth: --depth +takes_value "Only show commits within this many generations of a ref")
+            (@arg refs: --ref +takes_value +multiple number_of_values(1) "Only show the given refs")
+            (@arg label: --label +takes_value +multiple number_of_values(1) possible_value[subject author] "Add commit details to node labels")
+        )
+        (@subcommand serve =>
+            (about: "Serve a read-only web view of the repository on localhost")
+            (@arg port: -p --port +takes_value "Port to listen on (default 8000)")
+        )
+        (@subcommand tui =>
+            (about: "Browse the repository's history in a full-screen terminal interface")
         )
         (@subcommand commit =>
             (about: "Record changes to the repository")
             (@arg message: -m <MESSAGE> "Message to record")
+            (@arg sign: -S --sign "Sign the commit with the signing key")
         )
         (@subcommand log =>
             (about: "Show commit logs")
             (@arg OID: default_value[HEAD] "Commit object to show the log for")
+            (@arg oneline: --oneline conflicts_with[format] "Show each commit on a single line")
+            (@arg format: --format +takes_value "Format commits with a template, e.g. '%h %an %s'")
+            (@arg abbrev: --abbrev +takes_value "Length of abbreviated hashes (default 10)")
+            (@arg max_count: -n --("max-count") +takes_value "Limit the number of commits shown")
+            (@arg skip: --skip +takes_value "Skip a number of commits before showing any")
+            (@arg reverse: --reverse "Show commits in reverse order")
+            (@arg all: --all "Show commits reachable from any ref")
+            (@arg graph: --graph conflicts_with[reverse] "Draw the commit graph alongside the log")
+            (@arg graph_style: --("graph-style") +takes_value possible_value[ascii unicode] requires[graph] "Characters used to draw the graph")
+            (@arg author: --author +takes_value "Only show commits by authors matching a pattern")
+            (@arg grep: --grep +takes_value "Only show commits with messages matching a pattern")
+            (@arg since: --since +takes_value "Only show commits more recent than a date")
+            (@arg until: --until +takes_value "Only show commits older than a date")
+            (@arg pickaxe_string: -S +takes_value "Only show commits changing the number of occurrences of a string")
+            (@arg pickaxe_regex: -G +takes_value conflicts_with[pickaxe_string] "Only show commits adding or removing lines matching a pattern")
+            (@arg follow: --follow "Continue listing the history of a file beyond renames")
+            (@arg show_signature: --("show-signature") "Check and show the signature of each commit")
+            (@arg PATHS: +multiple +last "Only show commits changing these paths")
         )
         (@subcommand show =>
             (about: "Show commit object")
@@ -40,20 +70,51 @@ fn main() -> std::io::Result<()> {
         (@subcommand checkout =>
             (about: "Switch branches or restore working tree files")
             (@arg COMMIT: default_value[HEAD] "Commit or branch to checkout")
+            (@arg force: -f --force "Discard local changes to files which differ in the commit")
+            (@arg merge: -m --merge conflicts_with[force] "Merge local changes into files which differ in the commit")
         )
         (@subcommand reset =>
             (about: "Reset working directory to commit")
             (@arg COMMIT: default_value[HEAD] "Commit to reset to")
+            (@arg force: -f --force "Discard local changes to files which differ in the commit")
+            (@arg merge: -m --merge conflicts_with[force] "Merge local changes into files which differ in the commit")
         )
         (@subcommand tag =>
-            (about: "Create tag object referencing a commit")
-            (@arg NAME: +required "Tag name")
-            (@arg OID: default_value[HEAD] "Commit to tag")
+            (about: "Create, list or delete tags")
+            (@arg annotate: -a requires[message] "Create an annotated tag object")
+            (@arg message: -m +takes_value "Message for an annotated tag (implies -a)")
+            (@arg sign: -s requires[message] "Sign the annotated tag with the signing key")
+            (@arg list: -l --list conflicts_with[annotate message delete] "List tags, only those matching a pattern if given")
+            (@arg delete: -d conflicts_with[annotate message] requires[NAME] "Delete a tag")
+            (@arg NAME: "Tag name (or pattern to list)")
+            (@arg OID: default_value[HEAD] "Object to tag")
+        )
+        (@subcommand blame =>
+            (about: "Show what revision last modified each line of a file")
+            (@arg lines: -L +takes_value "Only blame lines <start>,<end>, <start>,+<count> or <start>, (to the end)")
+            (@arg porcelain: --porcelain "Show the results in a machine-readable format")
+            (@arg FILE: +required "File to annotate")
+            (@arg REV: default_value[HEAD] "Revision to annotate the file at")
+        )
+        (@subcommand reflog =>
+            (about: "Show the changes recorded for a ref")
+            (@arg REF: default_value[HEAD] "Ref to show the reflog of")
+        )
+        (@subcommand gc =>
+            (about: "Clean up the repository, expiring old reflog entries")
+            (@arg expire: --expire +takes_value "Expire reflog entries older than a date (default 90 days ago)")
         )
         (@subcommand branch =>
-            (about: "Create a new branch or show current branches")
+            (about: "Create, delete or rename branches, or show current branches")
+            (@arg delete: -d conflicts_with[force_delete move] requires[NAME] "Delete a branch which is merged into HEAD")
+            (@arg force_delete: -D conflicts_with[move] requires[NAME] "Delete a branch even if it isn't merged")
+            (@arg move: -m requires[NAME] "Rename a branch: <old> <new>, or just <new> for the current branch")
+            (@arg verbose: -v --verbose "Show the commit at the tip of each branch")
+            (@arg merged: --merged +takes_value min_values(0) "Only list branches merged into a commit (default HEAD)")
+            (@arg no_merged: --("no-merged") +takes_value min_values(0) conflicts_with[merged] "Only list branches not merged into a commit (default HEAD)")
+            (@arg contains: --contains +takes_value "Only list branches containing a commit")
             (@arg NAME: !required "Branch to create")
-            (@arg START: default_value[HEAD] "Start the branch at a given commit")
+            (@arg START: "Start the branch at a given commit (default HEAD)")
         )
     )
     // Some subcommands cannot be implemented using the macro syntax because
@@ -61,8 +122,13 @@ fn main() -> std::io::Result<()> {
     // characters
     .subcommand(
         SubCommand::with_name("hash-file")
-            .about("Hash a file into a stored object")
-            .arg(Arg::with_name("FILE").help("File to hash").required(true)),
+            .about("Compute the object ID of a file, optionally storing it")
+            .arg(Arg::with_name("FILE").help("File to hash").required(true))
+            .arg(
+                Arg::with_name("write")
+                    .short("w")
+                    .help("Store the file as an object"),
+            ),
     )
     .subcommand(
         SubCommand::with_name("cat-file")
@@ -86,7 +152,132 @@ fn main() -> std::io::Result<()> {
                     .required(true),
             ),
     )
+    .subcommand(
+        SubCommand::with_name("update-ref")
+            .about("Update refs, checking their old values first")
+            .arg(
+                Arg::with_name("delete")
+                    .short("d")
+                    .help("Delete the ref (checking it has OLDVALUE, given in place of NEWVALUE)"),
+            )
+            .arg(
+                Arg::with_name("message")
+                    .short("m")
+                    .takes_value(true)
+                    .value_name("REASON")
+                    .help("Reason recorded in the reflogs of the updated refs"),
+            )
+            .arg(
+                Arg::with_name("no-deref")
+                    .long("no-deref")
+                    .help("Update symbolic refs themselves rather than the refs they point to"),
+            )
+            .arg(
+                Arg::with_name("stdin")
+                    .long("stdin")
+                    .conflicts_with_all(&["delete", "REF"])
+                    .help("Read update, create, delete and verify commands from stdin and apply them all at once, or none if any fails"),
+            )
+            .arg(Arg::with_name("REF").help("Ref to update"))
+            .arg(Arg::with_name("NEWVALUE").help("Object to point the ref to"))
+            .arg(Arg::with_name("OLDVALUE").help("Value the ref must have before updating (all zeroes if it must not exist)")),
+    )
+    .subcommand(
+        SubCommand::with_name("check-ref-format")
+            .about("Check that a ref name is valid, exiting with an error if not")
+            .arg(
+                Arg::with_name("allow-onelevel")
+                    .long("allow-onelevel")
+                    .help("Allow names without a slash, e.g. branch names"),
+            )
+            .arg(
+                Arg::with_name("normalize")
+                    .long("normalize")
+                    .help("Remove leading slashes and repeated slashes, then print the name"),
+            )
+            .arg(
+                Arg::with_name("REFNAME")
+                    .help("Ref name to check")
+                    .required(true),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("gen-key")
+            .about("Create an Ed25519 key for signing commits and tags, and trust it"),
+    )
+    .subcommand(
+        SubCommand::with_name("verify-commit")
+            .about("Check that a commit is signed by a trusted key")
+            .arg(
+                Arg::with_name("COMMIT")
+                    .help("Commit to verify")
+                    .default_value("HEAD"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("verify-tag")
+            .about("Check that an annotated tag is signed by a trusted key")
+            .arg(Arg::with_name("TAG").help("Tag to verify").required(true)),
+    )
+    .subcommand(
+        SubCommand::with_name("pack-refs")
+            .about("Pack refs into a single file for faster lookups")
+            .arg(
+                Arg::with_name("all")
+                    .long("all")
+                    .help("Pack branches and all other refs as well as tags"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("format-patch")
+            .about("Prepare each commit in a range as a patch email")
+            .arg(
+                Arg::with_name("RANGE")
+                    .help("Commits to format, as <since>..<until> or <since> (up to HEAD)")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("output-directory")
+                    .short("o")
+                    .long("output-directory")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("Directory to write the patch files to"),
+            )
+            .arg(
+                Arg::with_name("stdout")
+                    .long("stdout")
+                    .conflicts_with("output-directory")
+                    .help("Print all patches to stdout as one mailbox"),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("am")
+            .about("Apply a series of patches from a mailbox")
+            .arg(
+                Arg::with_name("MBOX")
+                    .help("Mailbox files containing the patches")
+                    .required(true)
+                    .multiple(true),
+            ),
+    )
+    .subcommand(
+        SubCommand::with_name("export-html")
+            .about("Export a static HTML site for browsing the repository")
+            .arg(
+                Arg::with_name("DIR")
+                    .help("Directory to write the site to")
+                    .required(true),
+            ),
+    )
     .get_matches();
 
-    cli::handle(matches)
+    // A bad thread count is reported up front, rather than by whichever
+    // command first hashes files. Each kind of error exits with its own
+    // status so that scripts can distinguish them.
+    let result = gitox::parallel::thread_count().and_then(|_| cli::handle(matches));
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
 }
@@ -0,0 +1,91 @@
+use crate::error::{Error, Result};
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Environment variable giving the number of worker threads to hash and
+/// write files with, which defaults to the number of available CPUs.
+pub const THREADS_ENV: &str = "GITOX_THREADS";
+
+// Operations on fewer items than this finish quickly enough not to need a
+// progress report
+const PROGRESS_THRESHOLD: usize = 1000;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of worker threads to use.
+pub fn thread_count() -> Result<usize> {
+    match env::var(THREADS_ENV) {
+        Ok(threads) => match threads.parse() {
+            Ok(threads) if threads > 0 => Ok(threads),
+            _ => Err(Error::InvalidArgument(format!(
+                "{} must be a positive number, not {}",
+                THREADS_ENV, threads
+            ))),
+        },
+        Err(_) => Ok(thread::available_parallelism().map_or(1, |n| n.get())),
+    }
+}
+
+fn print_progress(label: &str, done: usize, total: usize) {
+    eprint!("\r{}: {}% ({}/{})", label, done * 100 / total, done, total);
+}
+
+/// Apply a function to each item on worker threads, returning the results in
+/// the order of the items. If any calls fail, the error for the earliest item
+/// is returned. Progress is reported on standard error under the given label
+/// when there are many items and it is a terminal.
+pub fn map<T, R, F>(items: &[T], label: &str, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let threads = thread_count()?.min(items.len());
+    let progress = items.len() >= PROGRESS_THRESHOLD && io::stderr().is_terminal();
+    if threads <= 1 && !progress {
+        return items.iter().map(f).collect();
+    }
+
+    // Workers take the next unclaimed item until none are left, or one fails
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<(usize, Result<R>)>> = Mutex::new(Vec::with_capacity(items.len()));
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut finished = Vec::new();
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let item = match items.get(i) {
+                            Some(item) => item,
+                            None => break,
+                        };
+                        let result = f(item);
+                        failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                        finished.push((i, result));
+                        done.fetch_add(1, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap().append(&mut finished);
+                })
+            })
+            .collect();
+        if progress {
+            while !workers.iter().all(|worker| worker.is_finished()) {
+                print_progress(label, done.load(Ordering::Relaxed), items.len());
+                thread::sleep(PROGRESS_INTERVAL);
+            }
+            print_progress(label, done.load(Ordering::Relaxed), items.len());
+            eprintln!(", done.");
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
@@ -0,0 +1,536 @@
+use crate::base::{Commit, Signature};
+use crate::data::Oid;
+use crate::diff;
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use chrono::DateTime;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Line used to separate messages within an mbox file. The date is fixed (as
+/// in Git) so the line can be recognised as coming from a generated patch.
+const MBOX_SEPARATOR_DATE: &str = "Mon Sep 17 00:00:00 2001";
+
+// Whether a line of a commit message would be mistaken for the start of the
+// next email, the end of the message or the start of the diff. Such lines
+// are quoted with a '>' (as in the "mboxrd" format), as are lines which only
+// look like them once leading '>'s are removed, so quoting can be undone.
+fn needs_quoting(line: &str) -> bool {
+    let unquoted = line.trim_start_matches('>');
+    unquoted.starts_with("From ")
+        || unquoted.trim_end() == "---"
+        || unquoted.starts_with("diff --git ")
+}
+
+fn quote_line(line: &str) -> String {
+    match needs_quoting(line) {
+        true => format!(">{}", line),
+        false => line.to_string(),
+    }
+}
+
+fn unquote_line(line: &str) -> &str {
+    match line.strip_prefix('>') {
+        Some(unquoted) if needs_quoting(unquoted) => unquoted,
+        _ => line,
+    }
+}
+
+/// A patch email parsed back out of a mailbox.
+pub struct Mail {
+    pub author: Signature,
+    pub subject: String,
+    pub body: String,
+    pub diff: Vec<u8>,
+}
+
+impl Mail {
+    /// Commit message reconstructed from the subject and body of the email.
+    pub fn message(&self) -> String {
+        if self.body.is_empty() {
+            self.subject.clone()
+        } else {
+            format!("{}\n\n{}", self.subject, self.body)
+        }
+    }
+}
+
+/// Name of the file a patch is written to, built from its position in the
+/// series and a sanitised copy of the commit subject.
+pub fn patch_filename(number: usize, commit: &Commit) -> String {
+    let mut slug = String::new();
+    for c in commit.subject().chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug: String = slug
+        .trim_matches(|c| c == '-' || c == '.')
+        .chars()
+        .take(52)
+        .collect();
+    format!("{:04}-{}.patch", number, slug.trim_end_matches('-'))
+}
+
+/// Format a commit as an mbox message: author, date and subject headers
+/// followed by the rest of the commit message and the diff against its parent.
+pub fn format_patch(
+    repo: &Repository,
+    oid: &Oid,
+    commit: &Commit,
+    number: usize,
+    total: usize,
+) -> Result<Vec<u8>> {
+    let author = match &commit.author {
+        Some(author) => author.clone(),
+        None => Signature::from_env("AUTHOR")?,
+    };
+    let prefix = if total > 1 {
+        format!("[PATCH {}/{}]", number, total)
+    } else {
+        "[PATCH]".to_string()
+    };
+
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => Some(repo.get_commit(parent)?.tree),
+        None => None,
+    };
+    let diff = repo.diff_trees_as_patch(
+        repo.get_tree(parent_tree.as_ref(), PathBuf::new())?,
+        repo.get_tree(Some(&commit.tree), PathBuf::new())?,
+    )?;
+
+    let mut output = Vec::new();
+    writeln!(output, "From {} {}", oid, MBOX_SEPARATOR_DATE)?;
+    writeln!(output, "From: {} <{}>", author.name, author.email)?;
+    writeln!(output, "Date: {}", author.time.to_rfc2822())?;
+    writeln!(output, "Subject: {} {}", prefix, commit.subject())?;
+    writeln!(output)?;
+    if !commit.body().is_empty() {
+        for line in commit.body().lines() {
+            writeln!(output, "{}", quote_line(line))?;
+        }
+    }
+    writeln!(output, "---")?;
+    output.extend(diff);
+    writeln!(output, "-- ")?;
+    writeln!(output, "gitox {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(output)?;
+    Ok(output)
+}
+
+fn split_lines(raw: &[u8]) -> Vec<&[u8]> {
+    raw.split_inclusive(|c| *c == b'\n').collect()
+}
+
+fn trim_line(line: &[u8]) -> String {
+    String::from_utf8_lossy(line)
+        .trim_end_matches(['\n', '\r'])
+        .to_string()
+}
+
+fn strip_subject_prefix(subject: &str) -> &str {
+    let subject = subject.trim();
+    if subject.starts_with("[PATCH") {
+        if let Some(end) = subject.find(']') {
+            return subject[end + 1..].trim_start();
+        }
+    }
+    subject
+}
+
+fn parse_from_header(from: &str) -> Result<(String, String)> {
+    let (name, rest) = from
+        .split_once('<')
+        .ok_or_else(|| Error::BadPatch(format!("Bad From header in patch: {}", from)))?;
+    let email = rest.trim_end().trim_end_matches('>');
+    Ok((name.trim().trim_matches('"').to_string(), email.to_string()))
+}
+
+fn parse_mail(lines: &[&[u8]]) -> Result<Mail> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut body_start = lines.len();
+
+    for (i, line) in lines.iter().enumerate() {
+        let line = trim_line(line);
+        if line.is_empty() {
+            body_start = i + 1;
+            break;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Folded continuation of the previous header
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let header = |key: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .ok_or_else(|| Error::BadPatch(format!("Missing the '{}' header", key)))
+    };
+    let (name, email) = parse_from_header(header("from")?)?;
+    let time = DateTime::parse_from_rfc2822(header("date")?)
+        .map_err(|_| Error::BadPatch("Malformed 'date' header".to_string()))?;
+    let subject = strip_subject_prefix(header("subject")?).to_string();
+
+    let mut body_lines = Vec::new();
+    let mut diff = Vec::new();
+    let mut past_separator = false;
+    for (i, line) in lines[body_start..].iter().enumerate() {
+        if line.starts_with(b"diff --git ") {
+            // The trailing signature is left in place, as it is ignored
+            // when the diff is parsed
+            diff = lines[body_start + i..].concat();
+            break;
+        } else if trim_line(line) == "---" {
+            // Anything between the separator and the diff (e.g. a diffstat)
+            // is not part of the message
+            past_separator = true;
+        } else if !past_separator {
+            body_lines.push(unquote_line(&trim_line(line)).to_string());
+        }
+    }
+
+    Ok(Mail {
+        author: Signature { name, email, time },
+        subject,
+        body: body_lines.join("\n").trim().to_string(),
+        diff,
+    })
+}
+
+/// Split an mbox file into its individual patch emails.
+pub fn parse_mailbox(raw: &[u8]) -> Result<Vec<Mail>> {
+    let lines = split_lines(raw);
+    let mut starts = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if line.starts_with(b"From ") && (i == 0 || trim_line(lines[i - 1]).is_empty()) {
+            starts.push(i);
+        }
+    }
+    if starts.is_empty() {
+        return Err(Error::BadPatch(
+            "Mailbox does not contain any patches".to_string(),
+        ));
+    }
+
+    let mut mails = Vec::new();
+    for (n, start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).cloned().unwrap_or(lines.len());
+        mails.push(parse_mail(&lines[start + 1..end])?);
+    }
+    Ok(mails)
+}
+
+#[derive(PartialEq)]
+enum Change {
+    New,
+    Deleted,
+    Modified,
+}
+
+struct Hunk {
+    old_start: usize,
+    old_lines: Vec<Vec<u8>>,
+    new_lines: Vec<Vec<u8>>,
+}
+
+struct FilePatch {
+    path: PathBuf,
+    change: Change,
+    hunks: Vec<Hunk>,
+}
+
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut fields = line.strip_prefix("@@ ")?.split(' ');
+    let (old_start, old_len) = diff::parse_hunk_range(fields.next()?.strip_prefix('-')?)?;
+    let (_, new_len) = diff::parse_hunk_range(fields.next()?.strip_prefix('+')?)?;
+    Some((old_start, old_len, new_len))
+}
+
+fn parse_diff(diff: &[u8]) -> Result<Vec<FilePatch>> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    // Lines still expected on the old and new sides of the current hunk
+    let mut remaining = (0, 0);
+    let mut last_marker = b' ';
+
+    for line in split_lines(diff) {
+        if line.starts_with(b"\\") {
+            // The line before had no trailing newline in the file
+            if let Some(hunk) = files.last_mut().and_then(|f| f.hunks.last_mut()) {
+                if last_marker != b'+' {
+                    hunk.old_lines.last_mut().map(|l| l.pop());
+                }
+                if last_marker != b'-' {
+                    hunk.new_lines.last_mut().map(|l| l.pop());
+                }
+            }
+            continue;
+        }
+
+        if remaining != (0, 0) {
+            let hunk = files.last_mut().unwrap().hunks.last_mut().unwrap();
+            // Mailers may strip the leading space from empty context lines
+            let (marker, text) = match line.split_first() {
+                Some((b'\n', _)) | None => (b' ', &b"\n"[..]),
+                Some((marker, text)) => (*marker, text),
+            };
+            match marker {
+                b' ' if remaining.0 > 0 && remaining.1 > 0 => {
+                    hunk.old_lines.push(text.to_vec());
+                    hunk.new_lines.push(text.to_vec());
+                    remaining = (remaining.0 - 1, remaining.1 - 1);
+                }
+                b'-' if remaining.0 > 0 => {
+                    hunk.old_lines.push(text.to_vec());
+                    remaining.0 -= 1;
+                }
+                b'+' if remaining.1 > 0 => {
+                    hunk.new_lines.push(text.to_vec());
+                    remaining.1 -= 1;
+                }
+                _ => return Err(Error::BadPatch("Corrupted hunk".to_string())),
+            }
+            last_marker = marker;
+            continue;
+        }
+
+        let line = trim_line(line);
+        if let Some(paths) = line.strip_prefix("diff --git a/") {
+            // Both paths are identical, so the first is half of the string
+            // without the " b/" separator
+            let path = &paths[..paths.len().saturating_sub(3) / 2];
+            files.push(FilePatch {
+                path: PathBuf::from(path),
+                change: Change::Modified,
+                hunks: Vec::new(),
+            });
+        } else if line.starts_with("Binary files ") {
+            return Err(Error::BadPatch(
+                "Binary patches are not supported".to_string(),
+            ));
+        } else if let Some(file) = files.last_mut() {
+            if line.starts_with("new file mode") {
+                file.change = Change::New;
+            } else if line.starts_with("deleted file mode") {
+                file.change = Change::Deleted;
+            } else if line.starts_with("@@ ") {
+                let (old_start, old_len, new_len) = parse_hunk_header(&line)
+                    .ok_or_else(|| Error::BadPatch(format!("Bad hunk header: {}", line)))?;
+                remaining = (old_len, new_len);
+                file.hunks.push(Hunk {
+                    old_start,
+                    old_lines: Vec::new(),
+                    new_lines: Vec::new(),
+                });
+            }
+        }
+    }
+
+    if remaining != (0, 0) {
+        return Err(Error::BadPatch("Truncated hunk".to_string()));
+    }
+    Ok(files)
+}
+
+fn find_hunk(lines: &[Vec<u8>], hunk: &Hunk, expected: usize) -> Option<usize> {
+    let len = hunk.old_lines.len();
+    if len > lines.len() {
+        return None;
+    }
+    let matches = |pos: usize| lines[pos..pos + len] == hunk.old_lines[..];
+    // Try the position given in the hunk header first, then search outwards
+    // in case earlier changes to the file have shifted it
+    let last = lines.len() - len;
+    let expected = expected.min(last);
+    (0..=last)
+        .flat_map(|d| vec![expected.checked_sub(d), Some(expected + d)])
+        .flatten()
+        .filter(|pos| *pos <= last)
+        .find(|pos| matches(*pos))
+}
+
+fn apply_file_patch(repo: &Repository, file: &FilePatch) -> Result<Option<Vec<u8>>> {
+    let path_str = file.path.to_string_lossy();
+    let path = repo.work_tree().join(&file.path);
+    let original = match file.change {
+        Change::New => {
+            if path.exists() {
+                return Err(Error::PatchDoesNotApply(format!(
+                    "{}: already exists",
+                    path_str
+                )));
+            }
+            Vec::new()
+        }
+        _ => fs::read(&path)
+            .map_err(|_| Error::PatchDoesNotApply(format!("{}: does not exist", path_str)))?,
+    };
+
+    let mut lines: Vec<Vec<u8>> = split_lines(&original).iter().map(|l| l.to_vec()).collect();
+    let mut offset: isize = 0;
+    for hunk in &file.hunks {
+        // A hunk with no old lines inserts after its start line
+        let start = if hunk.old_lines.is_empty() {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        };
+        let expected = (start as isize + offset).max(0) as usize;
+        let pos = find_hunk(&lines, hunk, expected)
+            .ok_or_else(|| Error::PatchDoesNotApply(path_str.to_string()))?;
+        lines.splice(
+            pos..pos + hunk.old_lines.len(),
+            hunk.new_lines.iter().cloned(),
+        );
+        offset += hunk.new_lines.len() as isize - hunk.old_lines.len() as isize;
+    }
+
+    let contents = lines.concat();
+    if file.change == Change::Deleted {
+        if !contents.is_empty() {
+            return Err(Error::PatchDoesNotApply(format!(
+                "{}: deleted file still has contents",
+                path_str
+            )));
+        }
+        return Ok(None);
+    }
+    Ok(Some(contents))
+}
+
+/// Apply a diff produced by `format_patch` to the working directory. Every
+/// file is patched in memory first, so nothing is written unless the whole
+/// diff applies cleanly.
+pub fn apply(repo: &Repository, diff: &[u8]) -> Result<()> {
+    let mut results = Vec::new();
+    for file in parse_diff(diff)? {
+        let result = apply_file_patch(repo, &file)?;
+        results.push((repo.work_tree().join(&file.path), result));
+    }
+
+    for (path, result) in results {
+        match result {
+            Some(contents) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, contents)?;
+            }
+            None => {
+                fs::remove_file(&path)?;
+                // Tidy up any directories left empty by the deletion
+                let mut dir = path.parent();
+                while let Some(parent) = dir.filter(|d| *d != repo.work_tree()) {
+                    if fs::remove_dir(parent).is_err() {
+                        break;
+                    }
+                    dir = parent.parent();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply a patch email to the working directory and record it as a commit
+/// with the author, date and message from the email.
+pub fn apply_mail(repo: &Repository, mail: &Mail) -> Result<Oid> {
+    apply(repo, &mail.diff)?;
+    repo.commit_with_author(&mail.message(), mail.author.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting_round_trips() {
+        for line in &[
+            "From me",
+            ">From me",
+            ">>From me",
+            "---",
+            ">---",
+            "--- not a separator",
+            "diff --git a/x b/x",
+            ">quoted text",
+            "plain",
+        ] {
+            assert_eq!(unquote_line(&quote_line(line)), *line);
+        }
+        assert_eq!(quote_line("From me"), ">From me");
+        assert_eq!(quote_line(">quoted text"), ">quoted text");
+    }
+
+    const MAILBOX: &str = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: A U Thor <author@example.com>
+Date: Thu, 1 Jan 2015 00:00:00 +0000
+Subject: [PATCH 1/2] First
+
+>From the start
+>---
+>diff --git a/x b/x
+>>From quoted
+---
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1 +1 @@
+-a
++b
+-- 
+gitox 0.1.0
+
+From 1111111111111111111111111111111111111111 Mon Sep 17 00:00:00 2001
+From: A U Thor <author@example.com>
+Date: Thu, 1 Jan 2015 00:00:00 +0000
+Subject: [PATCH 2/2] Second
+
+---
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1 +1 @@
+-b
++c
+";
+
+    #[test]
+    fn parse_mailbox_unquotes_escaped_lines() {
+        let mails = parse_mailbox(MAILBOX.as_bytes()).unwrap();
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0].subject, "First");
+        assert_eq!(
+            mails[0].body,
+            "From the start\n---\ndiff --git a/x b/x\n>From quoted"
+        );
+        assert!(mails[0].diff.starts_with(b"diff --git a/f b/f\n"));
+        assert_eq!(mails[1].message(), "Second");
+        assert_eq!(mails[1].author.email, "author@example.com");
+    }
+
+    #[test]
+    fn parse_mailbox_rejects_bad_mail() {
+        assert!(matches!(
+            parse_mailbox(b"not a mailbox\n"),
+            Err(Error::BadPatch(_))
+        ));
+        let missing_date = "From x Mon Sep 17 00:00:00 2001\nFrom: A <a@b>\nSubject: x\n\n";
+        assert!(matches!(
+            parse_mailbox(missing_date.as_bytes()),
+            Err(Error::BadPatch(_))
+        ));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::base::{Commit, Signature};
+use crate::data::Oid;
+
+/// Layout used to print a commit in `log` and `show`.
+pub enum Format {
+    /// Header, author, date and the indented commit message
+    Medium,
+    /// Abbreviated hash, decorations and subject on a single line
+    Oneline,
+    /// User supplied template with `%` placeholders (see `expand`)
+    Template(String),
+}
+
+impl Format {
+    /// Parse a `--format` value, accepting either a template or the name of
+    /// one of the built-in formats.
+    pub fn parse(format: &str) -> Format {
+        match format {
+            "medium" => Format::Medium,
+            "oneline" => Format::Oneline,
+            _ => {
+                let template = format
+                    .strip_prefix("format:")
+                    .or_else(|| format.strip_prefix("tformat:"))
+                    .unwrap_or(format);
+                Format::Template(template.to_string())
+            }
+        }
+    }
+}
+
+/// Date format used when showing signatures, e.g. "Sat Oct 17 12:00:00 2026 +0100".
+pub const DATE_FORMAT: &str = "%a %b %-d %H:%M:%S %Y %z";
+
+fn abbreviate(oid: &str, abbrev: usize) -> &str {
+    &oid[..abbrev.min(oid.len())]
+}
+
+fn decoration(refs: Option<&Vec<String>>) -> String {
+    match refs {
+        Some(refs) if !refs.is_empty() => format!(" ({})", refs.join(", ")),
+        _ => "".to_string(),
+    }
+}
+
+fn expand_signature(out: &mut String, spec: char, signature: Option<&Signature>) -> bool {
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return matches!(spec, 'n' | 'e' | 'd' | 't' | 'I'),
+    };
+    match spec {
+        'n' => out.push_str(&signature.name),
+        'e' => out.push_str(&signature.email),
+        'd' => out.push_str(&signature.time.format(DATE_FORMAT).to_string()),
+        't' => out.push_str(&signature.time.timestamp().to_string()),
+        'I' => out.push_str(&signature.time.to_rfc3339()),
+        _ => return false,
+    }
+    true
+}
+
+/// Expand the placeholders in a format template for a commit:
+///
+/// - `%H`/`%h`: commit hash (full/abbreviated)
+/// - `%T`/`%t`: tree hash (full/abbreviated)
+/// - `%P`/`%p`: parent hashes (full/abbreviated)
+/// - `%an`, `%ae`, `%ad`, `%at`, `%aI`: author name, email, date, timestamp
+///   and ISO 8601 date (and likewise `%c..` for the committer)
+/// - `%s`, `%b`, `%B`: subject, body and raw message
+/// - `%d`/`%D`: ref names, with and without the surrounding " (...)"
+/// - `%n`, `%%`: newline and a literal percent sign
+///
+/// Unknown placeholders are left in the output as they were written.
+pub fn expand(
+    template: &str,
+    oid: &Oid,
+    commit: &Commit,
+    refs: Option<&Vec<String>>,
+    abbrev: usize,
+) -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let spec = match chars.next() {
+            Some(spec) => spec,
+            None => {
+                out.push('%');
+                break;
+            }
+        };
+        match spec {
+            'H' => out.push_str(oid),
+            'h' => out.push_str(abbreviate(oid, abbrev)),
+            'T' => out.push_str(&commit.tree),
+            't' => out.push_str(abbreviate(&commit.tree, abbrev)),
+            'P' => out.push_str(&commit.parents.join(" ")),
+            'p' => out.push_str(
+                &commit
+                    .parents
+                    .iter()
+                    .map(|p| abbreviate(p, abbrev))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            's' => out.push_str(commit.subject()),
+            'b' => out.push_str(commit.body()),
+            'B' => out.push_str(&commit.message),
+            'd' => out.push_str(&decoration(refs)),
+            'D' => out.push_str(&refs.map(|refs| refs.join(", ")).unwrap_or_default()),
+            'n' => out.push('\n'),
+            '%' => out.push('%'),
+            'a' | 'c' => {
+                let signature = if spec == 'a' {
+                    commit.author.as_ref()
+                } else {
+                    commit.committer.as_ref()
+                };
+                match chars.peek() {
+                    Some(&field) if expand_signature(&mut out, field, signature) => {
+                        chars.next();
+                    }
+                    _ => {
+                        out.push('%');
+                        out.push(spec);
+                    }
+                }
+            }
+            _ => {
+                out.push('%');
+                out.push(spec);
+            }
+        }
+    }
+    out
+}
+
+/// Render a commit in the given format, without a trailing newline.
+pub fn format_commit(
+    format: &Format,
+    oid: &Oid,
+    commit: &Commit,
+    refs: Option<&Vec<String>>,
+    abbrev: usize,
+) -> String {
+    match format {
+        Format::Oneline => format!(
+            "{}{} {}",
+            abbreviate(oid, abbrev),
+            decoration(refs),
+            commit.subject()
+        ),
+        Format::Template(template) => expand(template, oid, commit, refs, abbrev),
+        Format::Medium => {
+            let mut lines = vec![format!("commit {}{}", oid, decoration(refs))];
+            if let Some(author) = &commit.author {
+                lines.push(format!("Author: {} <{}>", author.name, author.email));
+                lines.push(format!("Date:   {}", author.time.format(DATE_FORMAT)));
+            }
+            lines.push("".to_string());
+            for line in commit.message.lines() {
+                lines.push(format!("    {}", line));
+            }
+            lines.push("".to_string());
+            lines.join("\n")
+        }
+    }
+}
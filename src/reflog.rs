@@ -0,0 +1,191 @@
+use crate::base::Signature;
+use crate::data::{Oid, RefLock, NULL_OID};
+use crate::date;
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use chrono::{DateTime, FixedOffset};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+
+/// One change to a ref, recorded in its reflog in the same form as Git:
+/// `<old oid> <new oid> <committer signature>\t<reason>`.
+pub struct ReflogEntry {
+    pub old: Option<Oid>,
+    pub new: Option<Oid>,
+    pub committer: Signature,
+    pub reason: String,
+}
+
+fn oid_or_null(oid: Option<&str>) -> &str {
+    oid.unwrap_or(NULL_OID)
+}
+
+fn null_to_none(oid: &str) -> Option<Oid> {
+    match oid {
+        NULL_OID => None,
+        oid => Some(oid.to_string()),
+    }
+}
+
+impl std::fmt::Display for ReflogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}\t{}",
+            oid_or_null(self.old.as_deref()),
+            oid_or_null(self.new.as_deref()),
+            self.committer,
+            self.reason
+        )
+    }
+}
+
+impl FromStr for ReflogEntry {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let bad_entry = || Error::CorruptObject(format!("Corrupted reflog entry: {}", s));
+        let (header, reason) = s.split_once('\t').unwrap_or((s, ""));
+        let mut fields = header.splitn(3, ' ');
+        let old = fields.next().ok_or_else(bad_entry)?;
+        let new = fields.next().ok_or_else(bad_entry)?;
+        let committer = fields.next().ok_or_else(bad_entry)?.parse()?;
+        Ok(ReflogEntry {
+            old: null_to_none(old),
+            new: null_to_none(new),
+            committer,
+            reason: reason.to_string(),
+        })
+    }
+}
+
+impl Repository {
+    /// Record a change to a ref at the end of its reflog. This should only be
+    /// called while the ref is locked.
+    pub(crate) fn append_reflog(
+        &self,
+        ref_: &str,
+        old: Option<&str>,
+        new: Option<&str>,
+        reason: &str,
+    ) -> Result<()> {
+        let entry = ReflogEntry {
+            old: old.map(|oid| oid.to_string()),
+            new: new.map(|oid| oid.to_string()),
+            committer: Signature::from_env("COMMITTER")?,
+            // Each entry takes up exactly one line
+            reason: reason.replace('\n', " "),
+        };
+        let path = self.log_path(ref_);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_reflog(&self, ref_: &str) -> Result<()> {
+        match fs::remove_file(self.log_path(ref_)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The changes recorded for a ref, most recent first, so that entry `n`
+    /// is the value `<ref>@{n}` refers to. Refs without a reflog have no
+    /// entries.
+    pub fn reflog(&self, ref_: &str) -> Result<Vec<ReflogEntry>> {
+        let contents = match fs::read_to_string(self.log_path(ref_)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        contents
+            .lines()
+            .rev()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.parse())
+            .collect()
+    }
+
+    /// Resolve `<name>@{<n>}`, the value of a ref `n` changes ago, or
+    /// `<name>@{<date>}`, its value at a date. An empty name stands for HEAD.
+    pub(crate) fn get_reflog_oid(&self, name: &str, spec: &str) -> Result<Oid> {
+        let unknown = || Error::UnknownRevision(format!("{}@{{{}}}", name, spec));
+        let name = if name.is_empty() { "HEAD" } else { name };
+        let ref_ = self.full_ref_name(name)?.ok_or_else(unknown)?;
+        let entries = self.reflog(&ref_)?;
+        let oid = match spec.parse::<usize>() {
+            Ok(n) => entries.get(n).and_then(|entry| entry.new.clone()),
+            Err(_) => {
+                let time = date::parse_date(spec)?;
+                match entries.iter().find(|entry| entry.committer.time <= time) {
+                    Some(entry) => entry.new.clone(),
+                    // Before its first entry, the oldest known value is used:
+                    // the one it had before that entry, or the one the entry
+                    // gave it if it was created there
+                    None => entries
+                        .last()
+                        .and_then(|entry| entry.old.clone().or_else(|| entry.new.clone())),
+                }
+            }
+        };
+        oid.ok_or_else(unknown)
+    }
+
+    /// Remove reflog entries made before a time from every ref's reflog,
+    /// returning the number of entries removed.
+    pub fn expire_reflogs(&self, before: DateTime<FixedOffset>) -> Result<usize> {
+        let logs_dir = self.log_path("");
+        if !logs_dir.is_dir() {
+            return Ok(0);
+        }
+        // Logs are listed up front, so that the temporary files written while
+        // expiring them aren't found as well
+        let mut paths = Vec::new();
+        for entry in WalkDir::new(&logs_dir) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                paths.push(entry.into_path());
+            }
+        }
+        let mut expired = 0;
+        for path in paths {
+            let ref_ = path
+                .strip_prefix(&logs_dir)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            expired += self.expire_reflog(&ref_, &path, before)?;
+        }
+        Ok(expired)
+    }
+
+    fn expire_reflog(
+        &self,
+        ref_: &str,
+        path: &Path,
+        before: DateTime<FixedOffset>,
+    ) -> Result<usize> {
+        // Locking the ref stops entries being appended while the log is
+        // rewritten
+        let _lock = RefLock::acquire(self.ref_path(ref_))?;
+        let mut entries = self.reflog(ref_)?;
+        let count = entries.len();
+        entries.retain(|entry| entry.committer.time >= before);
+        if entries.len() == count {
+            return Ok(0);
+        }
+
+        let mut file = NamedTempFile::new_in(path.parent().unwrap_or(path))?;
+        for entry in entries.iter().rev() {
+            writeln!(file, "{}", entry)?;
+        }
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(count - entries.len())
+    }
+}
@@ -0,0 +1,217 @@
+use crate::data::RefValue;
+use crate::error::{Error, Result};
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the directory holding a repository's objects and refs, at the top
+/// of its working tree.
+pub const GIT_DIR: &str = ".gitox";
+
+/// Environment variable holding a colon separated list of directories which
+/// repository discovery won't search up into, e.g. to avoid slow network
+/// mounts.
+pub const CEILING_DIRECTORIES_ENV: &str = "GITOX_CEILING_DIRECTORIES";
+
+// Resolve the "." and ".." components of a path without consulting the
+// filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// Resolve symlinks in a directory so it can be compared with the (likewise
+// resolved) working tree, falling back to resolving it lexically if it
+// doesn't exist
+fn canonicalize_dir(dir: &Path) -> PathBuf {
+    fs::canonicalize(dir).unwrap_or_else(|_| normalize(dir))
+}
+
+fn ceiling_directories() -> Vec<PathBuf> {
+    env::var_os(CEILING_DIRECTORIES_ENV)
+        .map(|dirs| {
+            env::split_paths(&dirs)
+                .filter(|dir| dir.is_absolute())
+                .map(|dir| canonicalize_dir(&dir))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A handle on a repository, through which its objects, refs, trees, commits
+/// and diffs are accessed. All paths used by the repository are relative to
+/// its working tree, rather than the current directory.
+pub struct Repository {
+    work_tree: PathBuf,
+    git_dir: PathBuf,
+    // The signing key and trusted keys files, when given explicitly rather
+    // than found through the environment
+    key_paths: Option<(PathBuf, PathBuf)>,
+}
+
+impl Repository {
+    /// Open the existing repository whose working tree is at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Repository> {
+        Repository::open_with(path.as_ref().join(GIT_DIR), path)
+    }
+
+    /// Open an existing repository whose git directory is kept apart from
+    /// its working tree.
+    pub fn open_with<P: AsRef<Path>, Q: AsRef<Path>>(
+        git_dir: P,
+        work_tree: Q,
+    ) -> Result<Repository> {
+        let (git_dir, work_tree) = (git_dir.as_ref(), work_tree.as_ref());
+        if !git_dir.join("objects").is_dir() || !git_dir.join("refs").is_dir() {
+            return Err(Error::NotARepository(git_dir.to_path_buf()));
+        }
+        // Paths are made absolute so that they can be compared against the
+        // paths given by users
+        Ok(Repository {
+            git_dir: fs::canonicalize(git_dir)?,
+            work_tree: fs::canonicalize(work_tree)?,
+            key_paths: None,
+        })
+    }
+
+    /// Find the repository containing `path`, by searching it and then each
+    /// of its parents in turn for a `.gitox` directory. The search won't move
+    /// up into any of the directories listed in `GITOX_CEILING_DIRECTORIES`.
+    pub fn discover<P: AsRef<Path>>(path: P) -> Result<Repository> {
+        let start = fs::canonicalize(path)?;
+        let ceilings = ceiling_directories();
+        let mut dir = start.as_path();
+        loop {
+            if dir.join(GIT_DIR).is_dir() {
+                return Repository::open(dir);
+            }
+            match dir.parent() {
+                Some(parent) if !ceilings.iter().any(|ceiling| ceiling == parent) => dir = parent,
+                _ => break,
+            }
+        }
+        Err(Error::NotARepository(start))
+    }
+
+    /// Create an empty repository with its working tree at `path`, with HEAD
+    /// on the (as yet unborn) master branch.
+    pub fn init<P: AsRef<Path>>(path: P) -> Result<Repository> {
+        let work_tree = fs::canonicalize(path)?;
+        let repo = Repository {
+            git_dir: work_tree.join(GIT_DIR),
+            work_tree,
+            key_paths: None,
+        };
+        fs::create_dir_all(repo.git_dir.join("objects"))?;
+        fs::create_dir_all(repo.git_dir.join("refs"))?;
+        repo.update_ref(
+            "HEAD",
+            RefValue {
+                symbolic: true,
+                value: Some("refs/heads/master".to_string()),
+            },
+            true,
+            "init",
+        )?;
+        Ok(repo)
+    }
+
+    /// Use the given files for the key to sign objects with and the list of
+    /// trusted keys, instead of those named by `GITOX_SIGNING_KEY` and
+    /// `GITOX_TRUSTED_KEYS`.
+    pub fn with_key_paths<P: Into<PathBuf>, Q: Into<PathBuf>>(
+        mut self,
+        signing_key: P,
+        trusted_keys: Q,
+    ) -> Repository {
+        self.key_paths = Some((signing_key.into(), trusted_keys.into()));
+        self
+    }
+
+    pub(crate) fn key_paths(&self) -> Option<(&Path, &Path)> {
+        self.key_paths
+            .as_ref()
+            .map(|(signing_key, trusted_keys)| (signing_key.as_path(), trusted_keys.as_path()))
+    }
+
+    /// Top level directory of the files tracked by the repository.
+    pub fn work_tree(&self) -> &Path {
+        &self.work_tree
+    }
+
+    /// Directory holding the repository's objects and refs.
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// Convert a path given relative to `cwd` into the path of the same file
+    /// relative to the top of the working tree, as used in trees.
+    pub fn tree_path<P: AsRef<Path>>(&self, cwd: &Path, path: P) -> Result<PathBuf> {
+        let absolute = normalize(&canonicalize_dir(cwd).join(path.as_ref()));
+        match absolute.strip_prefix(&self.work_tree) {
+            Ok(relative) => Ok(relative.to_path_buf()),
+            Err(_) => Err(Error::InvalidArgument(format!(
+                "Path is outside the repository: {}",
+                path.as_ref().display()
+            ))),
+        }
+    }
+
+    /// Convert a path within the working tree (as used in trees) into a path
+    /// relative to `cwd`, for showing to the user.
+    pub fn display_path(&self, cwd: &Path, path: &Path) -> PathBuf {
+        let target = self.work_tree.join(path);
+        let cwd = canonicalize_dir(cwd);
+        let common = cwd
+            .components()
+            .zip(target.components())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut relative: PathBuf = cwd.components().skip(common).map(|_| "..").collect();
+        relative.extend(target.components().skip(common));
+        if relative.as_os_str().is_empty() {
+            relative.push(".");
+        }
+        relative
+    }
+
+    pub(crate) fn object_path(&self, oid: &str) -> PathBuf {
+        self.git_dir.join("objects").join(oid)
+    }
+
+    pub(crate) fn ref_path(&self, ref_: &str) -> PathBuf {
+        self.git_dir.join(ref_)
+    }
+
+    pub(crate) fn log_path(&self, ref_: &str) -> PathBuf {
+        self.git_dir.join("logs").join(ref_)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// An empty repository in a temporary directory, removed when the
+    /// directory is dropped. Its keys are kept in its git directory, so the
+    /// keys named by the environment are never touched.
+    pub(crate) fn temp_repo() -> (TempDir, Repository) {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let (signing_key, trusted_keys) = (
+            repo.git_dir().join("signing-key"),
+            repo.git_dir().join("trusted-keys"),
+        );
+        (dir, repo.with_key_paths(signing_key, trusted_keys))
+    }
+}
@@ -0,0 +1,342 @@
+use crate::base::Signature;
+use crate::data::{is_oid, ObjectType, Oid};
+use crate::error::{Error, Result};
+use crate::html::{self, Site, LOG_PAGE_SIZE};
+use crate::repository::Repository;
+use crate::visualize::{self, json_string};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// How long a client may take to send its request or receive the response
+/// before the connection is dropped, so that an idle client can't hold up
+/// the requests after it.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest request line and headers accepted, in bytes, so that a client
+/// can't make the server hold an endless request in memory.
+const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+// Every ref with its raw (possibly symbolic) value. New commits can only
+// become reachable by changing a ref, so the site only needs rebuilding when
+// this changes.
+type RefsState = Vec<(String, bool, Option<Oid>)>;
+
+fn refs_state(repo: &Repository) -> Result<RefsState> {
+    Ok(repo
+        .iter_refs(None, false)?
+        .map(|(name, value)| (name, value.symbolic, value.value))
+        .collect())
+}
+
+// The site as of the current refs, reusing the cached one if they are
+// unchanged
+fn current_site<'a, 'r>(
+    repo: &'r Repository,
+    cache: &'a mut Option<(RefsState, Site<'r>)>,
+) -> Result<&'a Site<'r>> {
+    let state = refs_state(repo)?;
+    let site = match cache.take() {
+        Some((cached, site)) if cached == state => site,
+        _ => Site::load(repo)?,
+    };
+    Ok(&cache.insert((state, site)).1)
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: &'static str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", status),
+        }
+    }
+}
+
+fn json_signature(signature: Option<&Signature>) -> String {
+    match signature {
+        Some(signature) => format!(
+            "{{\"name\": {}, \"email\": {}, \"date\": {}}}",
+            json_string(&signature.name),
+            json_string(&signature.email),
+            json_string(&signature.time.to_rfc3339())
+        ),
+        None => "null".to_string(),
+    }
+}
+
+fn json_commit_summary(repo: &Repository, oid: &Oid) -> Result<String> {
+    let commit = repo.get_commit(oid)?;
+    let parents: Vec<String> = commit.parents.iter().map(|p| json_string(p)).collect();
+    Ok(format!(
+        "{{\"oid\": {}, \"parents\": [{}], \"author\": {}, \"subject\": {}}}",
+        json_string(oid),
+        parents.join(", "),
+        json_signature(commit.author.as_ref()),
+        json_string(commit.subject())
+    ))
+}
+
+fn api_refs(site: &Site) -> String {
+    let refs: Vec<String> = site
+        .refs()
+        .iter()
+        .map(|(name, oid)| {
+            format!(
+                "{{\"name\": {}, \"target\": {}}}",
+                json_string(name),
+                json_string(oid)
+            )
+        })
+        .collect();
+    format!("[{}]", refs.join(", "))
+}
+
+fn api_log(site: &Site, page: usize) -> Result<Option<String>> {
+    if page < 1 || page > site.log_pages() {
+        return Ok(None);
+    }
+    let mut commits = Vec::new();
+    for oid in site
+        .commits()
+        .iter()
+        .skip((page - 1) * LOG_PAGE_SIZE)
+        .take(LOG_PAGE_SIZE)
+    {
+        commits.push(json_commit_summary(site.repo(), oid)?);
+    }
+    Ok(Some(format!(
+        "{{\"page\": {}, \"pages\": {}, \"commits\": [{}]}}",
+        page,
+        site.log_pages(),
+        commits.join(", ")
+    )))
+}
+
+fn api_commit(site: &Site, oid: &str) -> Result<Option<String>> {
+    if !site.commits().iter().any(|commit| commit == oid) {
+        return Ok(None);
+    }
+    let repo = site.repo();
+    let commit = repo.get_commit(&oid.to_string())?;
+    let parents: Vec<String> = commit.parents.iter().map(|p| json_string(p)).collect();
+    Ok(Some(format!(
+        "{{\"oid\": {}, \"tree\": {}, \"parents\": [{}], \"author\": {}, \"committer\": {}, \"message\": {}, \"diff\": {}}}",
+        json_string(oid),
+        json_string(&commit.tree),
+        parents.join(", "),
+        json_signature(commit.author.as_ref()),
+        json_signature(commit.committer.as_ref()),
+        json_string(&commit.message),
+        json_string(&String::from_utf8_lossy(&html::commit_diff(repo, &commit)?))
+    )))
+}
+
+fn api_tree(site: &Site, oid: &str) -> Result<Option<String>> {
+    if !site.has_object(oid) {
+        return Ok(None);
+    }
+    let mut entries = site.repo().get_tree_entries(Some(&oid.to_string()))?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"type\": {}, \"oid\": {}, \"name\": {}}}",
+                json_string(&entry.t.to_string()),
+                json_string(&entry.oid),
+                json_string(&entry.name)
+            )
+        })
+        .collect();
+    Ok(Some(format!("[{}]", entries.join(", "))))
+}
+
+fn api_blob(site: &Site, oid: &str) -> Result<Option<String>> {
+    if !site.has_object(oid) {
+        return Ok(None);
+    }
+    let contents = site
+        .repo()
+        .get_object(&oid.to_string(), Some(ObjectType::Blob))?
+        .contents;
+    let binary = html::is_binary(&contents);
+    let text = match binary {
+        true => "null".to_string(),
+        false => json_string(&String::from_utf8_lossy(&contents)),
+    };
+    Ok(Some(format!(
+        "{{\"oid\": {}, \"size\": {}, \"binary\": {}, \"content\": {}}}",
+        json_string(oid),
+        contents.len(),
+        binary,
+        text
+    )))
+}
+
+fn api_graph(repo: &Repository) -> Result<String> {
+    let graph = visualize::build(
+        repo,
+        &visualize::Options {
+            depth: None,
+            refs: None,
+            labels: visualize::Labels {
+                subject: true,
+                author: true,
+            },
+        },
+    )?;
+    Ok(graph.to_json())
+}
+
+// Answer a request to the JSON API, which mirrors the pages of the site and
+// likewise only shows objects reachable from a ref:
+//
+// - /api/refs
+// - /api/log?page=N
+// - /api/commit/<oid>
+// - /api/tree/<oid>
+// - /api/blob/<oid>
+// - /api/graph
+fn api(site: &Site, path: &str, query: &str) -> Result<Option<String>> {
+    let page = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("page="))
+        .map(|page| page.parse().unwrap_or(0))
+        .unwrap_or(1);
+    let result = match path.split_once('/').unwrap_or((path, "")) {
+        ("refs", "") => Ok(Some(api_refs(site))),
+        ("log", "") => api_log(site, page),
+        ("commit", oid) if is_oid(oid) => api_commit(site, oid),
+        ("tree", oid) => api_tree(site, oid),
+        ("blob", oid) => api_blob(site, oid),
+        ("graph", "") => api_graph(site.repo()).map(Some),
+        _ => Ok(None),
+    };
+    match result {
+        Err(Error::ObjectNotFound(_)) | Err(Error::WrongObjectType { .. }) => Ok(None),
+        result => result,
+    }
+}
+
+fn route(site: &Site, target: &str) -> Result<Response> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let found = match path.strip_prefix("/api/") {
+        Some(api_path) => {
+            api(site, api_path, query)?.map(|body| Response::ok("application/json", body + "\n"))
+        }
+        None => site
+            .render(path)?
+            .map(|body| Response::ok("text/html; charset=utf-8", body)),
+    };
+    Ok(found.unwrap_or_else(|| Response::error("404 Not Found")))
+}
+
+// Read the request line and the value of the Host header, or `None` if the
+// request is cut short or too long
+fn read_request(stream: &TcpStream) -> Result<Option<(String, Option<String>)>> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut read_line = || -> Result<Option<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        Ok(line
+            .strip_suffix('\n')
+            .map(|line| line.trim_end().to_string()))
+    };
+    let request_line = match read_line()? {
+        Some(request_line) => request_line,
+        None => return Ok(None),
+    };
+    let mut host = None;
+    loop {
+        match read_line()? {
+            Some(header) if header.is_empty() => return Ok(Some((request_line, host))),
+            Some(header) => {
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("host") {
+                        host = Some(value.trim().to_string());
+                    }
+                }
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+fn handle_connection<'r>(
+    repo: &'r Repository,
+    cache: &mut Option<(RefsState, Site<'r>)>,
+    hosts: &[String],
+    mut stream: TcpStream,
+) -> Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let (request_line, host) = read_request(&stream)?.unwrap_or_default();
+
+    let fields: Vec<&str> = request_line.split_whitespace().collect();
+    let response = match fields.as_slice() {
+        // Other hosts are refused, so that a web page can't read the
+        // repository by rebinding its own domain name to this address
+        [_, _, _] if !host.is_some_and(|host| hosts.contains(&host)) => {
+            Response::error("400 Bad Request")
+        }
+        ["GET", target, _] | ["HEAD", target, _] => current_site(repo, cache)
+            .and_then(|site| route(site, target))
+            .unwrap_or_else(|e| {
+                eprintln!("Error serving {}: {}", target, e);
+                Response::error("500 Internal Server Error")
+            }),
+        [_, _, _] => Response::error("405 Method Not Allowed"),
+        _ => Response::error("400 Bad Request"),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    if fields.first() != Some(&"HEAD") {
+        stream.write_all(response.body.as_bytes())?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Serve a read-only view of the repository over HTTP on localhost: the
+/// pages of the HTML site, along with a JSON API under "/api/". Requests are
+/// handled one at a time until the process is stopped, and new commits show
+/// up without restarting the server.
+pub fn serve(repo: &Repository, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let port = listener.local_addr()?.port();
+    let hosts = [format!("127.0.0.1:{}", port), format!("localhost:{}", port)];
+    let mut cache = None;
+    current_site(repo, &mut cache)?;
+    println!("Serving on http://{}/", hosts[0]);
+    for stream in listener.incoming() {
+        // A failed connection shouldn't bring down the server
+        if let Err(e) = stream
+            .map_err(Error::from)
+            .and_then(|stream| handle_connection(repo, &mut cache, &hosts, stream))
+        {
+            eprintln!("Error handling connection: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use crate::base::Signature;
+use crate::data::Oid;
+use crate::error::{Error, Result};
+use crate::repository::Repository;
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+/// Environment variable giving the path of the Ed25519 key to sign objects
+/// with, which is `signing-key` in the git directory by default. The key is
+/// stored as 64 hex digits.
+pub const SIGNING_KEY_ENV: &str = "GITOX_SIGNING_KEY";
+
+/// Environment variable giving the path of the file listing trusted public
+/// keys, which is `trusted-keys` in the git directory by default. Each line
+/// holds a key in hex followed by the name of its owner.
+pub const TRUSTED_KEYS_ENV: &str = "GITOX_TRUSTED_KEYS";
+
+// Signatures are kept in a header of the signed object, which is left out of
+// the payload that is signed
+const SIGNATURE_HEADER: &str = "signature";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// Where the headers of a raw commit or tag end, before the blank line
+// separating them from the message
+fn header_end(raw: &[u8]) -> usize {
+    raw.windows(2)
+        .position(|w| w == b"\n\n")
+        .unwrap_or(raw.len())
+}
+
+// Split a raw commit or tag into the exact bytes which were signed and the
+// value of its signature header, if it has one
+fn split_signature(raw: &[u8]) -> (Vec<u8>, Option<&[u8]>) {
+    let mut start: usize = 0;
+    for line in raw[..header_end(raw)].split(|&byte| byte == b'\n') {
+        if let Some(signature) = line
+            .strip_prefix(SIGNATURE_HEADER.as_bytes())
+            .and_then(|rest| rest.strip_prefix(b" "))
+        {
+            // The header is removed along with the newline before it
+            let mut payload = raw[..start.saturating_sub(1)].to_vec();
+            payload.extend_from_slice(&raw[start + line.len()..]);
+            return (payload, Some(signature));
+        }
+        start += line.len() + 1;
+    }
+    (raw.to_vec(), None)
+}
+
+/// The result of checking the signature of a commit or tag.
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    /// Signed by a trusted key, belonging to the named owner
+    Good {
+        key: String,
+        name: String,
+    },
+    /// Signed correctly, but by a key which isn't trusted
+    Untrusted {
+        key: String,
+    },
+    /// The signature doesn't match the contents of the object
+    Bad {
+        key: String,
+    },
+    Unsigned,
+}
+
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verification::Good { key, name } => {
+                write!(f, "Good signature from {} (key {})", name, &key[..16])
+            }
+            Verification::Untrusted { key } => {
+                write!(f, "Good signature from untrusted key {}", &key[..16])
+            }
+            Verification::Bad { key } => write!(f, "BAD signature from key {}", &key[..16]),
+            Verification::Unsigned => write!(f, "No signature"),
+        }
+    }
+}
+
+impl Repository {
+    fn signing_key_path(&self) -> PathBuf {
+        match self.key_paths() {
+            Some((signing_key, _)) => signing_key.to_path_buf(),
+            None => env::var_os(SIGNING_KEY_ENV)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| self.git_dir().join("signing-key")),
+        }
+    }
+
+    fn trusted_keys_path(&self) -> PathBuf {
+        match self.key_paths() {
+            Some((_, trusted_keys)) => trusted_keys.to_path_buf(),
+            None => env::var_os(TRUSTED_KEYS_ENV)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| self.git_dir().join("trusted-keys")),
+        }
+    }
+
+    fn signing_key(&self) -> Result<SigningKey> {
+        let path = self.signing_key_path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(Error::InvalidArgument(format!(
+                    "No signing key at {} (create one with gen-key)",
+                    path.display()
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let seed = from_hex(contents.trim()).ok_or_else(|| {
+            Error::InvalidArgument(format!("Invalid signing key in {}", path.display()))
+        })?;
+        Ok(SigningKey::from_bytes(&seed))
+    }
+
+    // Look up the owner of a public key in the trusted keys file
+    fn trusted_key_owner(&self, key: &str) -> Result<Option<String>> {
+        let contents = match fs::read_to_string(self.trusted_keys_path()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(contents
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| match line.split_once(' ') {
+                Some((trusted, name)) if trusted == key => Some(name.trim().to_string()),
+                None if line.trim() == key => Some(String::new()),
+                _ => None,
+            }))
+    }
+
+    /// Create a new signing key, trusted under the name of the current user,
+    /// returning its public key. An existing key is never replaced.
+    pub fn generate_signing_key(&self) -> Result<String> {
+        let path = self.signing_key_path();
+        let key = SigningKey::generate(&mut OsRng);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = match options.open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(Error::InvalidArgument(format!(
+                    "A signing key already exists at {}",
+                    path.display()
+                )))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        writeln!(file, "{}", to_hex(&key.to_bytes()))?;
+
+        let public = to_hex(key.verifying_key().as_bytes());
+        let owner = Signature::from_env("COMMITTER")?;
+        let mut trusted = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.trusted_keys_path())?;
+        writeln!(trusted, "{} {} <{}>", public, owner.name, owner.email)?;
+        Ok(public)
+    }
+
+    /// Sign a serialized commit or tag, adding the signature as a header.
+    pub(crate) fn sign_object(&self, unsigned: &[u8]) -> Result<Vec<u8>> {
+        let key = self.signing_key()?;
+        let signature = key.sign(unsigned);
+        let header = format!(
+            "\n{} {} {}",
+            SIGNATURE_HEADER,
+            to_hex(key.verifying_key().as_bytes()),
+            to_hex(&signature.to_bytes())
+        );
+        let end = header_end(unsigned);
+        let mut signed = unsigned.to_vec();
+        signed.splice(end..end, header.into_bytes());
+        Ok(signed)
+    }
+
+    /// Check the signature of a commit or tag against the trusted keys.
+    pub fn verify_object(&self, oid: &Oid) -> Result<Verification> {
+        let object = self.get_object(oid, None)?;
+        let (payload, header) = match split_signature(&object.contents) {
+            (payload, Some(header)) => (payload, header),
+            (_, None) => return Ok(Verification::Unsigned),
+        };
+        let bad_header = || Error::CorruptObject(format!("Corrupted signature in {}", oid));
+        let header = std::str::from_utf8(header).map_err(|_| bad_header())?;
+        let (key, signature) = header.split_once(' ').ok_or_else(bad_header)?;
+        let public = from_hex(key)
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or_else(bad_header)?;
+        let signature = from_hex(signature)
+            .map(|bytes| ed25519_dalek::Signature::from_bytes(&bytes))
+            .ok_or_else(bad_header)?;
+
+        let key = key.to_string();
+        if public.verify(&payload, &signature).is_err() {
+            return Ok(Verification::Bad { key });
+        }
+        Ok(match self.trusted_key_owner(&key)? {
+            Some(name) => Verification::Good { key, name },
+            None => Verification::Untrusted { key },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ObjectType;
+    use crate::repository::tests::temp_repo;
+    use tempfile::TempDir;
+
+    #[test]
+    fn split_signature_removes_the_header() {
+        let unsigned = b"tree 1234\nauthor A <a@b> 0 +0000\n\nmessage\nsignature in body\n";
+        assert_eq!(split_signature(unsigned), (unsigned.to_vec(), None));
+
+        let signed = b"tree 1234\nsignature key sig\nauthor A <a@b> 0 +0000\n\nmessage\n";
+        assert_eq!(
+            split_signature(signed),
+            (
+                b"tree 1234\nauthor A <a@b> 0 +0000\n\nmessage\n".to_vec(),
+                Some(&b"key sig"[..])
+            )
+        );
+    }
+
+    #[test]
+    fn from_hex_rejects_bad_input() {
+        assert_eq!(from_hex::<2>("0aff"), Some([0x0a, 0xff]));
+        assert_eq!(from_hex::<2>("0af"), None);
+        assert_eq!(from_hex::<2>("0afff0"), None);
+        assert_eq!(from_hex::<2>("zzzz"), None);
+        assert_eq!(from_hex::<2>("éa"), None);
+    }
+
+    fn signed_repo() -> (TempDir, Repository) {
+        let (dir, repo) = temp_repo();
+        repo.generate_signing_key().unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn verify_object_accepts_trusted_signatures() {
+        let (_dir, repo) = signed_repo();
+        let oid = repo.commit_signed("signed").unwrap();
+        assert!(matches!(
+            repo.verify_object(&oid).unwrap(),
+            Verification::Good { .. }
+        ));
+
+        let unsigned = repo.commit("unsigned").unwrap();
+        assert_eq!(
+            repo.verify_object(&unsigned).unwrap(),
+            Verification::Unsigned
+        );
+    }
+
+    #[test]
+    fn verify_object_rejects_tampered_payload() {
+        let (_dir, repo) = signed_repo();
+        let oid = repo.commit_signed("signed").unwrap();
+        let raw = repo.get_object(&oid, None).unwrap().contents;
+        let tampered = String::from_utf8(raw).unwrap().replace("signed", "forged");
+        let forged = repo
+            .hash_object(tampered.as_bytes(), ObjectType::Commit)
+            .unwrap();
+        assert!(matches!(
+            repo.verify_object(&forged).unwrap(),
+            Verification::Bad { .. }
+        ));
+    }
+
+    #[test]
+    fn verify_object_checks_the_exact_bytes_signed() {
+        let (_dir, repo) = signed_repo();
+        let signed = repo.sign_object(b"tree 1234\n\nmessage \xff\n").unwrap();
+        let oid = repo.hash_object(&signed, ObjectType::Commit).unwrap();
+        assert!(matches!(
+            repo.verify_object(&oid).unwrap(),
+            Verification::Good { .. }
+        ));
+
+        // Both bytes read as the same replacement character when decoded
+        // lossily, which mustn't let the signature carry over
+        let mut tampered = signed.clone();
+        let last = tampered.len() - 2;
+        tampered[last] = 0xfe;
+        let forged = repo.hash_object(&tampered, ObjectType::Commit).unwrap();
+        assert!(matches!(
+            repo.verify_object(&forged).unwrap(),
+            Verification::Bad { .. }
+        ));
+    }
+
+    #[test]
+    fn verify_object_rejects_untrusted_and_corrupt_signatures() {
+        let (_dir, repo) = signed_repo();
+        let oid = repo.commit_signed("signed").unwrap();
+        fs::remove_file(repo.trusted_keys_path()).unwrap();
+        assert!(matches!(
+            repo.verify_object(&oid).unwrap(),
+            Verification::Untrusted { .. }
+        ));
+
+        let corrupt = "tree 1234\nsignature nothex sig\n\nmessage\n";
+        let corrupt = repo
+            .hash_object(corrupt.as_bytes(), ObjectType::Commit)
+            .unwrap();
+        assert!(matches!(
+            repo.verify_object(&corrupt),
+            Err(Error::CorruptObject(_))
+        ));
+    }
+}
@@ -0,0 +1,519 @@
+use crate::base::{CheckoutMode, Tree};
+use crate::data::Oid;
+use crate::error::Result;
+use crate::graph;
+use crate::pretty::{self, DATE_FORMAT};
+use crate::repository::Repository;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+// Width of the ref sidebar, including its border
+const SIDEBAR_WIDTH: u16 = 28;
+
+// One line of the log pane: either a commit or a line continuing the graph
+// lanes between commits
+struct LogRow {
+    oid: Option<Oid>,
+    text: String,
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Log,
+    Refs,
+}
+
+// An action waiting for the user to type a name
+enum Prompt {
+    Tag(String),
+    Branch(String),
+}
+
+struct App<'a> {
+    repo: &'a Repository,
+    rows: Vec<LogRow>,
+    refs: Vec<(String, Oid)>,
+    focus: Focus,
+    /// Index into `rows` of the selected commit
+    selected: usize,
+    /// Index into `refs` of the selected ref
+    selected_ref: usize,
+    log_scroll: usize,
+    ref_scroll: usize,
+    detail: Vec<String>,
+    detail_oid: Option<Oid>,
+    detail_scroll: usize,
+    prompt: Option<Prompt>,
+    message: String,
+}
+
+fn load_rows(repo: &Repository) -> Result<Vec<LogRow>> {
+    let mut tips: Vec<Oid> = Vec::new();
+    for (_, refvalue) in repo.iter_refs(None, true)? {
+        if let Some(oid) = refvalue.value.filter(|oid| !tips.contains(oid)) {
+            tips.push(oid);
+        }
+    }
+    let oids: Vec<Oid> = repo.iter_commits_and_parents(tips.into_iter())?.collect();
+    let mut commits = HashMap::new();
+    let mut parents = HashMap::new();
+    for oid in &oids {
+        let commit = repo.get_commit(oid)?;
+        parents.insert(oid.clone(), commit.parents.clone());
+        commits.insert(oid.clone(), commit);
+    }
+    let oids = graph::topo_sort(oids, &parents);
+    let decorations = repo.get_oid_to_refs()?;
+
+    let mut graph = graph::Graph::new(graph::Style::Unicode);
+    let mut rows = Vec::new();
+    for oid in &oids {
+        let text = pretty::format_commit(
+            &pretty::Format::Oneline,
+            oid,
+            &commits[oid],
+            decorations.get(oid),
+            10,
+        );
+        let drawn = graph.draw_commit(oid, &parents[oid], &text);
+        for (i, line) in drawn.lines().enumerate() {
+            rows.push(LogRow {
+                oid: if i == 0 { Some(oid.clone()) } else { None },
+                text: line.to_string(),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+fn load_refs(repo: &Repository) -> Result<Vec<(String, Oid)>> {
+    let mut refs = Vec::new();
+    for (refname, refvalue) in repo.iter_refs(Some("refs/"), true)? {
+        if let Some(oid) = refvalue.value {
+            refs.push((refname, repo.peel(&oid)?));
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+fn load_detail(repo: &Repository, oid: &Oid) -> Result<Vec<String>> {
+    let commit = repo.get_commit(oid)?;
+    let mut lines = vec![format!("commit {}", oid)];
+    for parent in &commit.parents {
+        lines.push(format!("parent {}", parent));
+    }
+    if let Some(author) = &commit.author {
+        lines.push(format!("Author: {} <{}>", author.name, author.email));
+        lines.push(format!("Date:   {}", author.time.format(DATE_FORMAT)));
+    }
+    lines.push(String::new());
+    lines.extend(commit.message.lines().map(|line| format!("    {}", line)));
+    lines.push(String::new());
+
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => repo.get_tree(Some(&repo.get_commit(parent)?.tree), PathBuf::new())?,
+        None => Tree::new(),
+    };
+    let tree = repo.get_tree(Some(&commit.tree), PathBuf::new())?;
+    let diff = repo.diff_trees(parent_tree, tree)?;
+    lines.extend(
+        String::from_utf8_lossy(&diff)
+            .lines()
+            .map(|l| l.to_string()),
+    );
+    Ok(lines)
+}
+
+// Cut a line down to fit in the given number of columns, expanding tabs
+fn fit(line: &str, width: usize) -> String {
+    let line = line.replace('\t', "    ");
+    let fitted: String = line.chars().take(width).collect();
+    let pad = width - fitted.chars().count();
+    fitted + &" ".repeat(pad)
+}
+
+fn diff_colour(line: &str) -> Option<Color> {
+    if line.starts_with("+++") || line.starts_with("---") || line.starts_with("diff ") {
+        Some(Color::Yellow)
+    } else if line.starts_with('+') {
+        Some(Color::Green)
+    } else if line.starts_with('-') {
+        Some(Color::Red)
+    } else if line.starts_with("@@") {
+        Some(Color::Cyan)
+    } else {
+        None
+    }
+}
+
+impl<'a> App<'a> {
+    fn new(repo: &'a Repository) -> Result<App<'a>> {
+        let mut app = App {
+            repo,
+            rows: Vec::new(),
+            refs: Vec::new(),
+            focus: Focus::Log,
+            selected: 0,
+            selected_ref: 0,
+            log_scroll: 0,
+            ref_scroll: 0,
+            detail: Vec::new(),
+            detail_oid: None,
+            detail_scroll: 0,
+            prompt: None,
+            message: String::new(),
+        };
+        app.reload()?;
+        Ok(app)
+    }
+
+    // Re-read the repository, keeping the same commit selected if it is
+    // still shown
+    fn reload(&mut self) -> Result<()> {
+        let selected = self.selected_oid();
+        self.rows = load_rows(self.repo)?;
+        self.refs = load_refs(self.repo)?;
+        self.selected_ref = self.selected_ref.min(self.refs.len().saturating_sub(1));
+        self.selected = 0;
+        match selected {
+            Some(oid) => self.select_oid(&oid),
+            None => self.move_selection(0),
+        }
+        self.detail_oid = None;
+        Ok(())
+    }
+
+    // Reload, showing any error (e.g. a ref locked by another process) in
+    // the status line rather than leaving the interface
+    fn try_reload(&mut self) {
+        if let Err(e) = self.reload() {
+            self.message = format!("Error: {}", e);
+        }
+    }
+
+    fn selected_oid(&self) -> Option<Oid> {
+        self.rows.get(self.selected).and_then(|row| row.oid.clone())
+    }
+
+    fn select_oid(&mut self, oid: &Oid) {
+        match self
+            .rows
+            .iter()
+            .position(|row| row.oid.as_ref() == Some(oid))
+        {
+            Some(i) => self.selected = i,
+            None => self.move_selection(0),
+        }
+    }
+
+    // Move the selection by a number of commits, skipping over the lines
+    // which only continue the graph
+    fn move_selection(&mut self, by: isize) {
+        let commits: Vec<usize> = (0..self.rows.len())
+            .filter(|i| self.rows[*i].oid.is_some())
+            .collect();
+        if commits.is_empty() {
+            return;
+        }
+        let current = commits
+            .iter()
+            .position(|i| *i >= self.selected)
+            .unwrap_or(commits.len() - 1) as isize;
+        let next = (current + by).clamp(0, commits.len() as isize - 1);
+        self.selected = commits[next as usize];
+    }
+
+    fn update_detail(&mut self) -> Result<()> {
+        let oid = self.selected_oid();
+        if oid != self.detail_oid {
+            self.detail = match &oid {
+                Some(oid) => load_detail(self.repo, oid)?,
+                None => Vec::new(),
+            };
+            self.detail_oid = oid;
+            self.detail_scroll = 0;
+        }
+        Ok(())
+    }
+
+    // Name to check out for the current selection: the selected ref when
+    // the sidebar is focused (so that branches are checked out as branches),
+    // otherwise the selected commit
+    fn checkout_target(&self) -> Option<String> {
+        match self.focus {
+            Focus::Refs => self
+                .refs
+                .get(self.selected_ref)
+                .map(|(name, _)| name.strip_prefix("refs/heads/").unwrap_or(name).to_string()),
+            Focus::Log => self.selected_oid(),
+        }
+    }
+
+    fn run_prompt(&mut self, prompt: Prompt) -> Result<()> {
+        let oid = match self.selected_oid() {
+            Some(oid) => oid,
+            None => return Ok(()),
+        };
+        self.message = match prompt {
+            Prompt::Tag(name) if !name.is_empty() => {
+                self.repo.create_tag(&name, &oid)?;
+                format!("Created tag {} at {}", name, &oid[..10])
+            }
+            Prompt::Branch(name) if !name.is_empty() => {
+                self.repo.create_branch(&name, &oid)?;
+                format!("Created branch {} at {}", name, &oid[..10])
+            }
+            _ => "Cancelled".to_string(),
+        };
+        self.reload()
+    }
+
+    // Handle a key press, returning false once the user has asked to quit
+    fn handle_key(&mut self, key: KeyEvent, page: usize) -> Result<bool> {
+        if let Some(prompt) = self.prompt.as_mut() {
+            let name = match prompt {
+                Prompt::Tag(name) | Prompt::Branch(name) => name,
+            };
+            match key.code {
+                KeyCode::Char(c) => name.push(c),
+                KeyCode::Backspace => {
+                    name.pop();
+                }
+                KeyCode::Esc => {
+                    self.prompt = None;
+                    self.message = "Cancelled".to_string();
+                }
+                KeyCode::Enter => {
+                    let prompt = self.prompt.take().unwrap();
+                    if let Err(e) = self.run_prompt(prompt) {
+                        self.message = format!("Error: {}", e);
+                    }
+                }
+                _ => {}
+            }
+            return Ok(true);
+        }
+
+        self.message.clear();
+        match (key.code, &self.focus) {
+            (KeyCode::Char('q'), _) | (KeyCode::Esc, _) => return Ok(false),
+            (KeyCode::Tab, Focus::Log) => self.focus = Focus::Refs,
+            (KeyCode::Tab, Focus::Refs) => self.focus = Focus::Log,
+            (KeyCode::Down, Focus::Log) | (KeyCode::Char('j'), Focus::Log) => {
+                self.move_selection(1)
+            }
+            (KeyCode::Up, Focus::Log) | (KeyCode::Char('k'), Focus::Log) => self.move_selection(-1),
+            (KeyCode::Char('g'), Focus::Log) | (KeyCode::Home, Focus::Log) => {
+                self.move_selection(-(self.rows.len() as isize))
+            }
+            (KeyCode::Char('G'), Focus::Log) | (KeyCode::End, Focus::Log) => {
+                self.move_selection(self.rows.len() as isize)
+            }
+            (KeyCode::Down, Focus::Refs) | (KeyCode::Char('j'), Focus::Refs) => {
+                self.selected_ref = (self.selected_ref + 1).min(self.refs.len().saturating_sub(1))
+            }
+            (KeyCode::Up, Focus::Refs) | (KeyCode::Char('k'), Focus::Refs) => {
+                self.selected_ref = self.selected_ref.saturating_sub(1)
+            }
+            (KeyCode::Enter, Focus::Refs) => {
+                if let Some((_, oid)) = self.refs.get(self.selected_ref) {
+                    let oid = oid.clone();
+                    self.select_oid(&oid);
+                    self.focus = Focus::Log;
+                }
+            }
+            (KeyCode::PageDown, _) | (KeyCode::Char(' '), _) => {
+                let max = self.detail.len().saturating_sub(1);
+                self.detail_scroll = (self.detail_scroll + page).min(max);
+            }
+            (KeyCode::PageUp, _) => self.detail_scroll = self.detail_scroll.saturating_sub(page),
+            (KeyCode::Char('c'), _) => {
+                if let Some(target) = self.checkout_target() {
+                    self.message = match self.repo.checkout(&target, CheckoutMode::Safe) {
+                        Ok(_) => format!("Checked out {}", target),
+                        Err(e) => format!("Error: {}", e),
+                    };
+                    self.try_reload();
+                }
+            }
+            (KeyCode::Char('t'), Focus::Log) => self.prompt = Some(Prompt::Tag(String::new())),
+            (KeyCode::Char('b'), Focus::Log) => self.prompt = Some(Prompt::Branch(String::new())),
+            (KeyCode::Char('r'), _) => {
+                self.message = "Reloaded".to_string();
+                self.try_reload();
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        let sidebar = (SIDEBAR_WIDTH as usize).min(width / 3);
+        let main = width.saturating_sub(sidebar);
+        // The log takes the top half, the detail pane the rest bar the
+        // status line
+        let log_height = (height.saturating_sub(2) / 2).max(1);
+        let detail_height = height.saturating_sub(log_height + 2);
+
+        if self.selected < self.log_scroll {
+            self.log_scroll = self.selected;
+        } else if self.selected >= self.log_scroll + log_height {
+            self.log_scroll = self.selected + 1 - log_height;
+        }
+        let refs_height = height.saturating_sub(2).max(1);
+        if self.selected_ref < self.ref_scroll {
+            self.ref_scroll = self.selected_ref;
+        } else if self.selected_ref >= self.ref_scroll + refs_height {
+            self.ref_scroll = self.selected_ref + 1 - refs_height;
+        }
+        self.update_detail()?;
+
+        queue!(out, terminal::Clear(ClearType::All))?;
+
+        // Ref sidebar, with the branch HEAD is on marked
+        let head = self
+            .repo
+            .get_branch_name()?
+            .map(|name| format!("refs/heads/{}", name));
+        for row in 0..height.saturating_sub(1) {
+            queue!(out, cursor::MoveTo(0, row as u16))?;
+            let i = (self.ref_scroll + row).wrapping_sub(1);
+            let text = match (row, self.refs.get(i)) {
+                (0, _) => "Refs".to_string(),
+                (_, Some((name, _))) => {
+                    let marker = if head.as_ref() == Some(name) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    format!("{} {}", marker, name.strip_prefix("refs/").unwrap_or(name))
+                }
+                _ => String::new(),
+            };
+            let highlighted = self.focus == Focus::Refs && row > 0 && i == self.selected_ref;
+            if highlighted {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            } else if row == 0 {
+                queue!(out, SetAttribute(Attribute::Bold))?;
+            }
+            queue!(
+                out,
+                Print(fit(&text, sidebar.saturating_sub(1))),
+                SetAttribute(Attribute::Reset),
+                Print("│")
+            )?;
+        }
+
+        // Log pane
+        for row in 0..log_height {
+            let i = self.log_scroll + row;
+            queue!(out, cursor::MoveTo(sidebar as u16, row as u16))?;
+            let text = self.rows.get(i).map(|r| r.text.as_str()).unwrap_or("");
+            if i == self.selected {
+                let attribute = match self.focus {
+                    Focus::Log => Attribute::Reverse,
+                    Focus::Refs => Attribute::Underlined,
+                };
+                queue!(out, SetAttribute(attribute))?;
+            }
+            queue!(out, Print(fit(text, main)), SetAttribute(Attribute::Reset))?;
+        }
+
+        // Separator and commit detail pane
+        queue!(
+            out,
+            cursor::MoveTo(sidebar as u16, log_height as u16),
+            Print("─".repeat(main))
+        )?;
+        for row in 0..detail_height {
+            let line = self
+                .detail
+                .get(self.detail_scroll + row)
+                .map(|l| l.as_str())
+                .unwrap_or("");
+            queue!(
+                out,
+                cursor::MoveTo(sidebar as u16, (log_height + 1 + row) as u16)
+            )?;
+            if let Some(colour) = diff_colour(line) {
+                queue!(out, SetForegroundColor(colour))?;
+            }
+            queue!(out, Print(fit(line, main)), ResetColor)?;
+        }
+
+        // Status line, showing either a prompt, the result of the last
+        // action or the available keys
+        let status = match &self.prompt {
+            Some(Prompt::Tag(name)) => format!("New tag name: {}", name),
+            Some(Prompt::Branch(name)) => format!("New branch name: {}", name),
+            None if !self.message.is_empty() => self.message.clone(),
+            None => "q quit  j/k move  tab refs  enter jump  space/pgup scroll  \
+                     c checkout  t tag  b branch  r reload"
+                .to_string(),
+        };
+        queue!(
+            out,
+            cursor::MoveTo(0, height.saturating_sub(1) as u16),
+            SetAttribute(Attribute::Reverse),
+            Print(fit(&status, width)),
+            SetAttribute(Attribute::Reset)
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn run(repo: &Repository, out: &mut impl Write) -> Result<()> {
+    let mut app = App::new(repo)?;
+    loop {
+        app.draw(out)?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let (_, height) = terminal::size()?;
+            let page = (height as usize / 2).max(1);
+            if !app.handle_key(key, page)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Run the full-screen terminal interface until the user quits: a log of
+/// all refs drawn with graph lanes, a sidebar of branches and tags, and the
+/// details and diff of the selected commit. The terminal is restored
+/// afterwards, even if an error occurred.
+pub fn tui(repo: &Repository) -> Result<()> {
+    let mut out = io::stdout();
+    let _guard = TerminalGuard::enter(&mut out)?;
+    run(repo, &mut out)
+}
+
+// Puts the terminal into raw mode on the alternate screen, and restores it
+// when dropped, so that it is restored even if the interface panics
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter(out: &mut impl Write) -> Result<TerminalGuard> {
+        terminal::enable_raw_mode()?;
+        let guard = TerminalGuard;
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
@@ -0,0 +1,461 @@
+use crate::data::Oid;
+use crate::error::{Error, Result};
+use crate::graph;
+use crate::repository::Repository;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Output formats for the commit graph.
+pub enum Format {
+    Dot,
+    Svg,
+    Json,
+    Mermaid,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format> {
+        match format {
+            "dot" | "gv" => Ok(Format::Dot),
+            "svg" => Ok(Format::Svg),
+            "json" => Ok(Format::Json),
+            "mermaid" | "mmd" => Ok(Format::Mermaid),
+            _ => Err(Error::InvalidArgument(format!(
+                "Unknown graph format: {}",
+                format
+            ))),
+        }
+    }
+}
+
+/// Which details of a commit are shown in its node.
+pub struct Labels {
+    pub subject: bool,
+    pub author: bool,
+}
+
+pub struct Options {
+    /// Only include commits within this many generations of a ref
+    pub depth: Option<usize>,
+    /// Only include these refs (and the commits they lead to)
+    pub refs: Option<Vec<String>>,
+    pub labels: Labels,
+}
+
+struct RefNode {
+    name: String,
+    target: String,
+    symbolic: bool,
+    // The commit the ref resolves to
+    tip: Option<Oid>,
+}
+
+struct CommitNode {
+    oid: Oid,
+    parents: Vec<Oid>,
+    subject: String,
+    author: Option<String>,
+    label: String,
+}
+
+/// The commits reachable from a set of refs, along with the refs themselves.
+pub struct CommitGraph {
+    refs: Vec<RefNode>,
+    commits: Vec<CommitNode>,
+    /// Parents of each commit among those in the graph
+    parents: HashMap<Oid, Vec<Oid>>,
+}
+
+fn ref_selected(refname: &str, selected: &Option<Vec<String>>) -> bool {
+    let selected = match selected {
+        Some(selected) => selected,
+        None => return true,
+    };
+    // Refs can be selected by their full name or as they would be given to
+    // get_oid, e.g. "master" for "refs/heads/master"
+    selected.iter().any(|name| {
+        ["", "refs/", "refs/tags/", "refs/heads/"]
+            .iter()
+            .any(|prefix| format!("{}{}", prefix, name) == refname)
+    })
+}
+
+// Commits reachable from the tips, in the order of iter_commits_and_parents
+// but stopping after `depth` generations when given
+fn reachable_commits(repo: &Repository, tips: &[Oid], depth: Option<usize>) -> Result<Vec<Oid>> {
+    let depth = match depth {
+        Some(depth) => depth,
+        None => {
+            return Ok(repo
+                .iter_commits_and_parents(tips.iter().cloned())?
+                .collect())
+        }
+    };
+
+    let mut generations: HashMap<Oid, usize> = HashMap::new();
+    let mut queue: VecDeque<(Oid, usize)> = tips.iter().map(|oid| (oid.clone(), 1)).collect();
+    while let Some((oid, generation)) = queue.pop_front() {
+        if generation > depth || generations.contains_key(&oid) {
+            continue;
+        }
+        generations.insert(oid.clone(), generation);
+        for parent in repo.get_commit(&oid)?.parents {
+            queue.push_back((parent, generation + 1));
+        }
+    }
+    Ok(repo
+        .iter_commits_and_parents(tips.iter().cloned())?
+        .filter(|oid| generations.contains_key(oid))
+        .collect())
+}
+
+/// Collect the graph of commits and refs described by the options.
+pub fn build(repo: &Repository, options: &Options) -> Result<CommitGraph> {
+    let mut refs = Vec::new();
+    let mut tips: Vec<Oid> = Vec::new();
+    for (refname, refvalue) in repo.iter_refs(None, false)? {
+        if !ref_selected(&refname, &options.refs) {
+            continue;
+        }
+        if let Some(value) = refvalue.value {
+            // Symbolic refs also lead to the commit they resolve to, and
+            // annotated tags are shown pointing at the commit they tag
+            let (value, tip) = match refvalue.symbolic {
+                true => (value, repo.get_ref(&refname, true)?.value),
+                false => {
+                    let value = repo.peel(&value)?;
+                    (value.clone(), Some(value))
+                }
+            };
+            if let Some(tip) = tip.as_ref().filter(|tip| !tips.contains(tip)) {
+                tips.push(tip.clone());
+            }
+            refs.push(RefNode {
+                name: refname,
+                target: value,
+                symbolic: refvalue.symbolic,
+                tip,
+            });
+        }
+    }
+
+    let oids = reachable_commits(repo, &tips, options.depth)?;
+    let mut commits = Vec::new();
+    let mut parents = HashMap::new();
+    for oid in &oids {
+        let commit = repo.get_commit(oid)?;
+        let author = commit.author.as_ref().map(|a| a.name.clone());
+        let mut label = vec![oid[..10].to_string()];
+        if options.labels.subject {
+            label.push(commit.subject().to_string());
+        }
+        if options.labels.author {
+            label.push(author.clone().unwrap_or_else(|| "unknown".to_string()));
+        }
+        parents.insert(oid.clone(), commit.parents.clone());
+        commits.push(CommitNode {
+            oid: oid.clone(),
+            parents: commit.parents.clone(),
+            subject: commit.subject().to_string(),
+            author,
+            label: label.join("\n"),
+        });
+    }
+
+    // Order commits so that they can be laid out from newest to oldest.
+    // Commits past the depth limit aren't loaded, so lines of history through
+    // them stop at the limit.
+    let parents = graph::visible_parents(&oids, parents, |_| Ok(Vec::new()))?;
+    let order: HashMap<Oid, usize> = graph::topo_sort(oids, &parents)
+        .into_iter()
+        .enumerate()
+        .map(|(i, oid)| (oid, i))
+        .collect();
+    commits.sort_by_key(|commit| order[&commit.oid]);
+
+    Ok(CommitGraph {
+        refs,
+        commits,
+        parents,
+    })
+}
+
+fn escape_quoted(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escape a string for inclusion in XML or HTML text and attributes.
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Quote a string for inclusion in JSON output.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl CommitGraph {
+    fn shown(&self) -> HashSet<&Oid> {
+        self.commits.iter().map(|commit| &commit.oid).collect()
+    }
+
+    // Refs which lead to a shown commit, along with the node each points at.
+    // Symbolic refs point at their target ref if it is shown, and otherwise
+    // straight at the commit they resolve to. Refs to commits left out by the
+    // depth limit aren't shown.
+    fn shown_refs(&self) -> Vec<(&RefNode, &str)> {
+        let shown = self.shown();
+        let names: HashSet<&str> = self
+            .refs
+            .iter()
+            .filter(|r| !r.symbolic && shown.contains(&r.target))
+            .map(|r| r.name.as_str())
+            .collect();
+        self.refs
+            .iter()
+            .filter_map(|r| match &r.tip {
+                _ if names.contains(r.name.as_str()) => Some((r, r.target.as_str())),
+                _ if r.symbolic && names.contains(r.target.as_str()) => {
+                    Some((r, r.target.as_str()))
+                }
+                Some(tip) if r.symbolic && shown.contains(tip) => Some((r, tip.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn render(&self, format: &Format) -> String {
+        match format {
+            Format::Dot => self.to_dot(),
+            Format::Svg => self.to_svg(),
+            Format::Json => self.to_json(),
+            Format::Mermaid => self.to_mermaid(),
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let shown = self.shown();
+        let mut lines = vec!["digraph commits {".to_string()];
+        for (ref_node, target) in self.shown_refs() {
+            let name = escape_quoted(&ref_node.name);
+            lines.push(format!("\"{}\" [shape=note]", name));
+            lines.push(format!("\"{}\" -> \"{}\"", name, escape_quoted(target)));
+        }
+        for commit in &self.commits {
+            lines.push(format!(
+                "\"{}\" [shape=box style=filled label=\"{}\"]",
+                commit.oid,
+                escape_quoted(&commit.label)
+            ));
+            for parent in commit.parents.iter().filter(|p| shown.contains(p)) {
+                lines.push(format!("\"{}\" -> \"{}\"", commit.oid, parent));
+            }
+        }
+        lines.push("}".to_string());
+        lines.join("\n")
+    }
+
+    pub fn to_json(&self) -> String {
+        let shown = self.shown();
+        let refs: Vec<String> = self
+            .shown_refs()
+            .into_iter()
+            .map(|(r, target)| {
+                format!(
+                    "    {{\"name\": {}, \"target\": {}, \"symbolic\": {}}}",
+                    json_string(&r.name),
+                    json_string(target),
+                    r.symbolic && target == r.target
+                )
+            })
+            .collect();
+        let commits: Vec<String> = self
+            .commits
+            .iter()
+            .map(|c| {
+                let parents: Vec<String> = c
+                    .parents
+                    .iter()
+                    .filter(|p| shown.contains(p))
+                    .map(|p| json_string(p))
+                    .collect();
+                format!(
+                    "    {{\"oid\": {}, \"parents\": [{}], \"subject\": {}, \"author\": {}, \"label\": {}}}",
+                    json_string(&c.oid),
+                    parents.join(", "),
+                    json_string(&c.subject),
+                    c.author.as_deref().map(json_string).unwrap_or_else(|| "null".to_string()),
+                    json_string(&c.label)
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"refs\": [\n{}\n  ],\n  \"commits\": [\n{}\n  ]\n}}",
+            refs.join(",\n"),
+            commits.join(",\n")
+        )
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let shown = self.shown();
+        let refs = self.shown_refs();
+        // Mermaid identifiers must be plain words, so refs are numbered
+        let ref_ids: HashMap<&str, String> = refs
+            .iter()
+            .map(|(r, _)| r)
+            .enumerate()
+            .map(|(i, r)| (r.name.as_str(), format!("ref{}", i)))
+            .collect();
+        let node_id = |target: &str| match ref_ids.get(target) {
+            Some(id) => id.clone(),
+            None => format!("c{}", target),
+        };
+        let mermaid_label = |label: &str| label.replace('"', "#quot;").replace('\n', "<br>");
+
+        let mut lines = vec!["graph TD".to_string()];
+        for (r, target) in &refs {
+            lines.push(format!(
+                "    {}[/\"{}\"/]",
+                ref_ids[r.name.as_str()],
+                mermaid_label(&r.name)
+            ));
+            lines.push(format!(
+                "    {} --> {}",
+                ref_ids[r.name.as_str()],
+                node_id(target)
+            ));
+        }
+        for c in &self.commits {
+            lines.push(format!("    c{}[\"{}\"]", c.oid, mermaid_label(&c.label)));
+            for parent in c.parents.iter().filter(|p| shown.contains(p)) {
+                lines.push(format!("    c{} --> c{}", c.oid, parent));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Draw the graph as an SVG image, with each commit on its own row and
+    /// lines of history laid out in columns as in `log --graph`.
+    pub fn to_svg(&self) -> String {
+        const MARGIN: usize = 20;
+        const ROW_HEIGHT: usize = 28;
+        const COLUMN_WIDTH: usize = 18;
+        const CHAR_WIDTH: usize = 7;
+        let colours = [
+            "#1f77b4", "#d62728", "#2ca02c", "#9467bd", "#ff7f0e", "#17becf",
+        ];
+
+        let oids: Vec<Oid> = self.commits.iter().map(|c| c.oid.clone()).collect();
+        let parents = &self.parents;
+        let columns = graph::columns(&oids, parents);
+        let positions: HashMap<&Oid, (usize, usize)> = oids
+            .iter()
+            .zip(columns.iter())
+            .enumerate()
+            .map(|(row, (oid, col))| {
+                (
+                    oid,
+                    (MARGIN + col * COLUMN_WIDTH, MARGIN + row * ROW_HEIGHT),
+                )
+            })
+            .collect();
+
+        // Refs pointing at each commit, with symbolic refs shown by name
+        let mut decorations: HashMap<&str, Vec<&str>> = HashMap::new();
+        for r in self.refs.iter().filter(|r| !r.symbolic) {
+            decorations.entry(&r.target).or_default().push(&r.name);
+        }
+        for r in self.refs.iter().filter(|r| r.symbolic) {
+            let target = self.refs.iter().find(|t| t.name == r.target && !t.symbolic);
+            if let Some(target) = target {
+                decorations.entry(&target.target).or_default().push(&r.name);
+            }
+        }
+
+        let text_x = MARGIN + (columns.iter().max().map(|c| c + 1).unwrap_or(0)) * COLUMN_WIDTH;
+        let mut edges = Vec::new();
+        let mut nodes = Vec::new();
+        let mut max_text = 0;
+        for (commit, col) in self.commits.iter().zip(columns.iter()) {
+            let (x, y) = positions[&commit.oid];
+            let colour = colours[col % colours.len()];
+            for parent in &parents[&commit.oid] {
+                let (px, py) = positions[parent];
+                edges.push(if px == x {
+                    format!(
+                        "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" stroke-width=\"2\"/>",
+                        x, y, px, py, colour
+                    )
+                } else {
+                    // Curve across to the parent's column, then run down it
+                    let turn = y + ROW_HEIGHT;
+                    format!(
+                        "<path d=\"M {} {} C {} {} {} {} {} {} L {} {}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\"/>",
+                        x, y, x, turn, px, y, px, turn, px, py, colour
+                    )
+                });
+            }
+            nodes.push(format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"6\" fill=\"{}\"><title>{}</title></circle>",
+                x, y, colour, commit.oid
+            ));
+
+            let mut text_x = text_x + 8;
+            if let Some(names) = decorations.get(commit.oid.as_str()) {
+                for name in names {
+                    let width = name.len() * CHAR_WIDTH + 8;
+                    nodes.push(format!(
+                        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"18\" rx=\"3\" fill=\"#fff3b0\" stroke=\"#b59b00\"/>\
+                         <text x=\"{}\" y=\"{}\">{}</text>",
+                        text_x,
+                        y - 10,
+                        width,
+                        text_x + 4,
+                        y + 4,
+                        escape_xml(name)
+                    ));
+                    text_x += width + 6;
+                }
+            }
+            let label = commit.label.replace('\n', "  ");
+            nodes.push(format!(
+                "<text x=\"{}\" y=\"{}\">{}</text>",
+                text_x,
+                y + 4,
+                escape_xml(&label)
+            ));
+            max_text = max_text.max(text_x + label.len() * CHAR_WIDTH);
+        }
+
+        let width = max_text + MARGIN;
+        let height = 2 * MARGIN + self.commits.len().saturating_sub(1) * ROW_HEIGHT;
+        let mut svg = vec![
+            format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+                width, height, width, height
+            ),
+            "<style>text { font-family: monospace; font-size: 12px; }</style>".to_string(),
+            format!("<rect width=\"{}\" height=\"{}\" fill=\"white\"/>", width, height),
+        ];
+        svg.extend(edges);
+        svg.extend(nodes);
+        svg.push("</svg>".to_string());
+        svg.join("\n")
+    }
+}